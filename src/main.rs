@@ -1,11 +1,14 @@
-use crate::png::{CompressionLevel, DecodedPng};
+use crate::png::{CompressionLevel, CrcMode, DecodeOptions, DecodeReport, DecodedPng, Interlacing, PhysicalDimensions};
+use crate::png::apng::{is_animated, AnimatedPng};
+use crate::png::icc::IccProfile;
+use crate::png::stream::{transcode, StreamingDecoder};
 use anyhow::{bail, Context};
 use argon2::{Algorithm, Argon2, ParamsBuilder, Version};
 use clap::Parser;
 use futures_lite::stream::StreamExt;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use rand::{TryRng};
-use std::io::Write;
+use std::io::{BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 mod png;
@@ -33,6 +36,67 @@ struct Args {
     #[arg(short = 'm', long = "level", required = false, default_value = "lossless")]
     compression_level: CompressionLevel,
 
+    #[arg(long = "interlace", required = false, default_value = "none")]
+    interlacing: Interlacing,
+
+    #[arg(long = "crc", required = false, default_value = "strict")]
+    crc_mode: CrcMode,
+
+    #[arg(long = "recover")]
+    recover: bool,
+
+    #[arg(long = "set-text", value_name = "KEYWORD=TEXT", value_parser = parse_key_value)]
+    set_text: Vec<(String, String)>,
+
+    #[arg(long = "remove-text", value_name = "KEYWORD")]
+    remove_text: Vec<String>,
+
+    #[arg(long = "to-srgb")]
+    to_srgb: bool,
+
+    #[arg(long = "extract-icc", value_name = "PATH")]
+    extract_icc: Option<String>,
+
+    #[arg(long = "embed-icc", value_name = "PATH")]
+    embed_icc: Option<String>,
+
+    #[arg(long = "dpi", conflicts_with = "ppm")]
+    dpi: Option<f64>,
+
+    #[arg(long = "ppm", value_name = "PIXELS_PER_METER")]
+    ppm: Option<u32>,
+
+    #[arg(long = "auto-orient")]
+    auto_orient: bool,
+
+    #[arg(long = "redact-exif")]
+    redact_exif: bool,
+
+    // Without a color the file's bKGD is required
+    #[arg(long = "flatten", value_name = "RRGGBB", num_args = 0..=1, value_parser = parse_color)]
+    flatten: Option<Option<[u8; 3]>>,
+
+    #[arg(long = "tone-map")]
+    tone_map: bool,
+
+    #[arg(long = "force-lossy")]
+    force_lossy: bool,
+
+    #[arg(long = "info")]
+    info: bool,
+
+    // Builds an APNG out of the PNG files in a directory, in file name order
+    #[arg(long = "animate", value_name = "DIR")]
+    animate: Option<String>,
+
+    // Milliseconds per frame, the last value is used for the remaining frames
+    #[arg(long = "delay", value_name = "MS", value_delimiter = ',', default_value = "100")]
+    delay: Vec<u16>,
+
+    // 0 loops forever
+    #[arg(long = "loops", default_value_t = 0)]
+    loops: u32,
+
     #[arg(short = 'o', required = false)]
     outfile: Option<String>,
 
@@ -40,6 +104,21 @@ struct Args {
     out_dir: Option<String>,
 }
 
+fn parse_key_value(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEYWORD=TEXT, got {:?}", arg))
+}
+
+fn parse_color(arg: &str) -> Result<[u8; 3], String> {
+    let hex = arg.trim_start_matches('#');
+    let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(red), Some(green), Some(blue)) => Ok([red, green, blue]),
+        _ => Err(format!("expected a hex color like ffffff, got {:?}", arg)),
+    }
+}
+
 #[derive(Clone)]
 struct KeyObject {
     key: [u8; 32],
@@ -147,14 +226,11 @@ async fn get_png_files_async(dir: &str) -> anyhow::Result<Vec<PathBuf>> {
     while let Some(entry) = entries.next().await {
         let entry = entry?;
         let path = entry.path();
-        if let Ok(meta) = entry.metadata().await {
-            if meta.is_file() {
-                if let Some(ext) = path.extension() {
-                    if ext.to_string_lossy().to_lowercase() == "png" {
-                        png_files.push(path);
-                    }
-                }
-            }
+        if let Ok(meta) = entry.metadata().await
+            && meta.is_file()
+            && let Some(ext) = path.extension()
+            && ext.to_string_lossy().to_lowercase() == "png" {
+            png_files.push(path);
         }
     }
     png_files.sort();
@@ -186,24 +262,254 @@ fn get_output_path(input_path: &str, out_dir: Option<&str>, suffix: &str) -> Str
     }
 }
 
+// Settings shared by every file processed in one run
+#[derive(Clone, Default)]
+struct ProcessOptions {
+    compression_level: CompressionLevel,
+    interlacing: Interlacing,
+    decode: DecodeOptions,
+    set_text: Vec<(String, String)>,
+    remove_text: Vec<String>,
+    to_srgb: bool,
+    extract_icc: Option<String>,
+    embed_icc: Option<IccProfile>,
+    physical: Option<PhysicalDimensions>,
+    auto_orient: bool,
+    redact_exif: bool,
+    // Some(None) flattens onto bKGD only
+    flatten: Option<Option<[u8; 3]>>,
+    tone_map: bool,
+    force_lossy: bool,
+}
+
+fn report_damage(input_file: &str, report: &DecodeReport, pb: &ProgressBar) {
+    for message in report.messages() {
+        pb.println(format!("{}: {}", input_file, message));
+    }
+}
+
+// Metadata edits from the command line, applied before the image is written back out
+async fn apply_edits(image: &mut DecodedPng, options: &ProcessOptions) -> anyhow::Result<()> {
+    if let Some(path) = &options.extract_icc {
+        let profile = image.info.icc_profile.as_ref().ok_or_else(|| anyhow::anyhow!("Image has no embedded ICC profile"))?;
+        smol::fs::write(path, &profile.data).await.with_context(|| format!("Failed to write {}", path))?;
+    }
+    if let Some(profile) = &options.embed_icc {
+        image.set_icc_profile(profile.clone());
+    }
+    if options.tone_map {
+        image.tone_map_to_sdr()?;
+    }
+    if options.to_srgb {
+        image.normalize_to_srgb()?;
+    }
+    if let Some(fallback) = options.flatten {
+        image.flatten_alpha(fallback)?;
+    }
+    if let Some(physical) = options.physical {
+        image.info.physical = Some(physical);
+    }
+    if options.auto_orient {
+        image.apply_orientation();
+    }
+    if options.redact_exif && let Some(exif) = image.info.exif.as_mut() {
+        exif.redact();
+    }
+    for keyword in &options.remove_text {
+        image.remove_text(keyword);
+    }
+    for (keyword, text) in &options.set_text {
+        image.set_text(keyword, text)?;
+    }
+    Ok(())
+}
+
+// What --info describes. Only animations need their pixels, other files are checked row by row without keeping any.
+async fn read_info(input_file: &str, key: Option<[u8; 32]>, options: &DecodeOptions) -> anyhow::Result<DecodedPng> {
+    if options.recover {
+        return Ok(DecodedPng::read_from_file_async(input_file, key, options.clone(), &ProgressBar::hidden()).await?);
+    }
+    let file = std::fs::File::open(input_file)?;
+    let mut decoder = StreamingDecoder::new(std::io::BufReader::new(file), key.as_ref(), options.clone())?;
+    let image = smol::unblock(move || {
+        if is_animated(&decoder.metadata()) {
+            return decoder.decode(&ProgressBar::hidden());
+        }
+        while decoder.next_row()?.is_some() {}
+        Ok(decoder.metadata())
+    }).await?;
+    Ok(image)
+}
+
+// Everything --info reports about a file
+fn describe(image: &DecodedPng) -> Vec<String> {
+    let info = &image.info;
+    let mut lines = vec![
+        format!("Dimensions: {}x{}", info.width, info.height),
+        format!("Color: {:?}, {} bit", info.image_type, info.bit_depth),
+        format!("Interlaced: {}", if info.interlace == 1 { "Adam7" } else { "no" }),
+    ];
+    if let Some(intent) = info.srgb {
+        lines.push(format!("sRGB: {:?}", intent));
+    }
+    if let Some(gamma) = info.gamma {
+        lines.push(format!("Gamma: {:.5}", gamma as f64 / 100000.0));
+    }
+    if let Some(profile) = &info.icc_profile {
+        lines.push(format!("ICC profile: {} ({} bytes)", profile.name, profile.data.len()));
+    }
+    if let Some(physical) = info.physical {
+        lines.push(match physical.dpi() {
+            Some((x, y)) => format!("Physical size: {}x{} pixels per meter ({:.0}x{:.0} DPI)", physical.x, physical.y, x, y),
+            None => format!("Pixel aspect ratio: {}:{}", physical.x, physical.y),
+        });
+    }
+    if let Some(cicp) = info.cicp {
+        lines.push(format!("cICP: primaries {}, transfer {}{}{}", cicp.colour_primaries, cicp.transfer_function,
+            if cicp.full_range { "" } else { ", narrow range" }, if cicp.is_hdr() { " (HDR)" } else { "" }));
+    }
+    if let Some(display) = info.mastering_display {
+        lines.push(format!("Mastering display: {}-{} cd/m²", display.min_luminance as f64 / 10000.0, display.max_luminance as f64 / 10000.0));
+    }
+    if let Some(level) = info.content_light_level {
+        lines.push(format!("Content light level: MaxCLL {} cd/m², MaxFALL {} cd/m²", level.max_cll as f64 / 10000.0, level.max_fall as f64 / 10000.0));
+    }
+    if let Some(bits) = info.significant_bits {
+        let channels: Vec<String> = bits.rgb.iter().chain(bits.alpha.iter()).map(|bits| bits.to_string()).collect();
+        lines.push(format!("Significant bits: {}", channels.join(",")));
+    }
+    if let Some([red, green, blue]) = info.background {
+        lines.push(format!("Background: {:02x}{:02x}{:02x}", red >> 8, green >> 8, blue >> 8));
+    }
+    if let Some(exif) = &info.exif {
+        lines.push(format!("EXIF: {} bytes{}", exif.data.len(), if exif.has_location() { ", has GPS location" } else { "" }));
+        if let Some(orientation) = exif.orientation() {
+            lines.push(format!("Orientation: {}", orientation));
+        }
+    }
+    if is_animated(image) {
+        lines.push(match AnimatedPng::from_decoded(image.clone(), &Default::default()) {
+            Ok(animation) => {
+                let duration: f64 = animation.frames.iter().map(|frame| frame.control.delay()).sum();
+                let plays = match animation.num_plays {
+                    0 => "loops forever".to_string(),
+                    plays => format!("plays {} times", plays),
+                };
+                format!("Animation: {} frames over {:.2}s, {}", animation.frames.len(), duration, plays)
+            },
+            Err(e) => format!("Animation: {}", e),
+        });
+    }
+    for text in &image.text {
+        lines.push(format!("Text: {} = {}", text.keyword, text.text));
+    }
+    lines
+}
+
+// Quantizing PQ/HLG to a few bits per channel crushes the HDR range, tone mapped images are SDR again
+fn check_lossy_hdr(image: &DecodedPng, options: &ProcessOptions) -> anyhow::Result<()> {
+    if !options.force_lossy && image.check_lossy_hdr(&options.compression_level).is_err() {
+        bail!("Image is HDR (PQ/HLG), use -m lossless, --tone-map or --force-lossy");
+    }
+    Ok(())
+}
+
+// Animations are rewritten frame by frame, edits that change pixels only work on still images
+fn read_animation(input_file: &str, image: DecodedPng, options: &ProcessOptions) -> anyhow::Result<AnimatedPng> {
+    if options.to_srgb || options.tone_map || options.flatten.is_some() || options.auto_orient {
+        bail!("{}: --to-srgb, --tone-map, --flatten and --auto-orient don't support animated PNGs", input_file);
+    }
+    AnimatedPng::from_decoded(image, &options.decode.limits).with_context(|| input_file.to_string())
+}
+
+// Files whose RGBA pixels take at least this many bytes are re-encoded row by row instead of decoded whole
+const STREAMING_THRESHOLD: u64 = 256 * 1024 * 1024;
+
+// Re-encodes a large file through StreamingEncoder when nothing needs the whole image. The rows are copied
+// in the source's sample format and interlacing, only lossless output can skip the pixel reduction the
+// regular path does. Returns false without writing anything when the file has to take the regular path.
+#[allow(clippy::too_many_arguments)]
+async fn stream_file(
+    input_file: &str,
+    output: &str,
+    decryption_key: Option<[u8; 32]>,
+    encryption_key: Option<[u8; 32]>,
+    compression_level: CompressionLevel,
+    options: &ProcessOptions,
+    threshold: u64,
+    pb: &ProgressBar,
+) -> anyhow::Result<bool> {
+    // Text after the image data is only read at the end, too late for the text edits
+    let needs_image = options.to_srgb || options.tone_map || options.flatten.is_some() || options.auto_orient
+        || !options.set_text.is_empty() || !options.remove_text.is_empty();
+    if options.decode.recover || needs_image || !matches!(compression_level, CompressionLevel::Lossless) {
+        return Ok(false);
+    }
+
+    let file = std::fs::File::open(input_file).with_context(|| format!("Failed to open {}", input_file))?;
+    let decoder = StreamingDecoder::new(BufReader::new(file), decryption_key.as_ref(), options.decode.clone())?;
+    let mut image = decoder.metadata();
+    let interlace_matches = match options.interlacing {
+        Interlacing::None => image.info.interlace == 0,
+        Interlacing::Adam7 => image.info.interlace == 1,
+        Interlacing::Auto => true,
+    };
+    if (image.info.width as u64) * (image.info.height as u64) * 4 < threshold || !interlace_matches || is_animated(&image) {
+        return Ok(false);
+    }
+
+    apply_edits(&mut image, options).await?;
+    pb.set_message("Streaming image...");
+    let file = std::fs::File::create(output).with_context(|| format!("Failed to create {}", output))?;
+    let result = smol::unblock(move || {
+        transcode(decoder, image, BufWriter::new(file), compression_level, encryption_key.as_ref()).map(|(_, report)| report)
+    }).await;
+    match result {
+        Ok(report) => report_damage(input_file, &report, pb),
+        Err(e) => {
+            let _ = smol::fs::remove_file(output).await;
+            return Err(e.into());
+        }
+    }
+    pb.inc(7);
+    Ok(true)
+}
+
 async fn process_file_encrypt_async(
     input_file: &str,
     output_file: Option<String>,
     out_dir: Option<&str>,
     key: [u8; 32],
-    compression_level: CompressionLevel,
+    options: &ProcessOptions,
     pb: &ProgressBar,
 ) -> anyhow::Result<()> {
-    let image = DecodedPng::read_from_file_async(input_file, None, pb).await?;
-
     let output = output_file.unwrap_or_else(|| get_output_path(input_file, out_dir, "_encrypted"));
 
     if let Some(out_dir) = out_dir {
         smol::fs::create_dir_all(out_dir).await?;
     }
+    if stream_file(input_file, &output, None, Some(key), options.compression_level.clone(), options, STREAMING_THRESHOLD, pb).await? {
+        return Ok(());
+    }
+
+    let mut image = DecodedPng::read_from_file_async(input_file, None, options.decode.clone(), pb).await?;
+    report_damage(input_file, &image.report, pb);
+
+    if is_animated(&image) {
+        let mut animation = read_animation(input_file, image, options)?;
+        apply_edits(&mut animation.image, options).await?;
+        check_lossy_hdr(&animation.image, options).with_context(|| input_file.to_string())?;
+        animation
+            .save_optimized_async(&output, options.compression_level.clone(), Some(key), pb)
+            .await?;
+        return Ok(());
+    }
+
+    apply_edits(&mut image, options).await?;
+    check_lossy_hdr(&image, options).with_context(|| input_file.to_string())?;
 
     image
-        .save_optimized_async(&output, compression_level, Some(key), pb)
+        .save_optimized_async(&output, options.compression_level.clone(), options.interlacing, Some(key), pb)
         .await?;
     Ok(())
 }
@@ -213,18 +519,34 @@ async fn process_file_decrypt_async(
     output_file: Option<String>,
     out_dir: Option<&str>,
     key: [u8; 32],
+    options: &ProcessOptions,
     pb: &ProgressBar,
 ) -> anyhow::Result<()> {
-    let image = DecodedPng::read_from_file_async(input_file, Some(key), pb).await?;
-
     let output = output_file.unwrap_or_else(|| get_output_path(input_file, out_dir, "_decrypted"));
 
     if let Some(out_dir) = out_dir {
         smol::fs::create_dir_all(out_dir).await?;
     }
+    if stream_file(input_file, &output, Some(key), None, CompressionLevel::Lossless, options, STREAMING_THRESHOLD, pb).await? {
+        return Ok(());
+    }
+
+    let mut image = DecodedPng::read_from_file_async(input_file, Some(key), options.decode.clone(), pb).await?;
+    report_damage(input_file, &image.report, pb);
+
+    if is_animated(&image) {
+        let mut animation = read_animation(input_file, image, options)?;
+        apply_edits(&mut animation.image, options).await?;
+        animation
+            .save_optimized_async(&output, CompressionLevel::Lossless, None, pb)
+            .await?;
+        return Ok(());
+    }
+
+    apply_edits(&mut image, options).await?;
 
     image
-        .save_optimized_async(&output, CompressionLevel::Lossless, None, pb)
+        .save_optimized_async(&output, CompressionLevel::Lossless, options.interlacing, None, pb)
         .await?;
     Ok(())
 }
@@ -233,6 +555,97 @@ const PROGRESS_TEMPLATE: &str = "{spinner:.green} [{elapsed_precise}] {bar:40.cy
 
 async fn async_main() -> anyhow::Result<()> {
     let args = Args::parse();
+    let embed_icc = match &args.embed_icc {
+        Some(path) => {
+            let data = smol::fs::read(path).await.with_context(|| format!("Failed to read {}", path))?;
+            Some(IccProfile::new("ICC Profile", data)?)
+        },
+        None => None,
+    };
+    let physical = match (args.dpi, args.ppm) {
+        (Some(dpi), _) if !dpi.is_finite() || dpi <= 0.0 => bail!("--dpi must be positive"),
+        (Some(dpi), _) => Some(PhysicalDimensions::from_dpi(dpi)),
+        (None, Some(ppm)) => Some(PhysicalDimensions::from_ppm(ppm)),
+        (None, None) => None,
+    };
+    if args.extract_icc.is_some() && args.directory.is_some() {
+        bail!("--extract-icc needs a single input file (-i)");
+    }
+    let options = ProcessOptions {
+        compression_level: args.compression_level.clone(),
+        interlacing: args.interlacing,
+        decode: DecodeOptions { crc_mode: args.crc_mode, recover: args.recover, ..Default::default() },
+        set_text: args.set_text.clone(),
+        remove_text: args.remove_text.clone(),
+        to_srgb: args.to_srgb,
+        extract_icc: args.extract_icc.clone(),
+        embed_icc,
+        physical,
+        auto_orient: args.auto_orient,
+        redact_exif: args.redact_exif,
+        flatten: args.flatten,
+        tone_map: args.tone_map,
+        force_lossy: args.force_lossy,
+    };
+
+    if args.info {
+        let input_file = args
+            .input_file
+            .ok_or_else(|| anyhow::anyhow!("Input file (-i) required for --info"))?;
+        // The image data of encrypted files can only be decoded with their key
+        let key = match &args.key_path {
+            Some(key_path) => Some(KeyObject::load_key_async(key_path).await?.key),
+            None => None,
+        };
+        let image = read_info(&input_file, key, &options.decode).await
+            .with_context(|| format!("Failed to read {} (pass -k for encrypted files)", input_file))?;
+        println!("{}", input_file);
+        for line in describe(&image).iter().chain(image.report.messages().iter()) {
+            println!("  {}", line);
+        }
+        return Ok(());
+    }
+
+    if let Some(dir) = &args.animate {
+        let output = args
+            .outfile
+            .ok_or_else(|| anyhow::anyhow!("Output file (-o) required for --animate"))?;
+        let key = match (args.encrypt, &args.key_path) {
+            (true, Some(key_path)) => Some(KeyObject::load_key_async(key_path).await?.key),
+            (true, None) => bail!("Key file required when encrypting"),
+            (false, _) => None,
+        };
+        let frame_files = get_png_files_async(dir).await?;
+        if frame_files.is_empty() {
+            bail!("No PNG files found in directory: {}", dir);
+        }
+
+        let pb = ProgressBar::new(4);
+        pb.set_style(
+            ProgressStyle::with_template(PROGRESS_TEMPLATE)?
+                .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ "),
+        );
+        pb.enable_steady_tick(std::time::Duration::from_millis(100));
+
+        let mut frames = Vec::with_capacity(frame_files.len());
+        for file_path in &frame_files {
+            let input_file = file_path.to_string_lossy().to_string();
+            let mut image = DecodedPng::read_from_file_async(&input_file, None, options.decode.clone(), &ProgressBar::hidden()).await
+                .with_context(|| format!("Failed to read {}", input_file))?;
+            report_damage(&input_file, &image.report, &pb);
+            apply_edits(&mut image, &options).await?;
+            frames.push(image);
+        }
+        let animation = AnimatedPng::from_frames(frames, &args.delay, args.loops)?;
+        check_lossy_hdr(&animation.image, &options)?;
+        animation
+            .save_optimized_async(&output, options.compression_level.clone(), key, &pb)
+            .await?;
+
+        pb.finish();
+        println!("Wrote {} frame(s) to {}", frame_files.len(), output);
+        return Ok(());
+    }
 
     if let Some(password) = args.password {
         let key_path = args
@@ -294,7 +707,7 @@ async fn async_main() -> anyhow::Result<()> {
             let input_file = file_path.to_string_lossy().to_string();
             let out_dir_clone = args.out_dir.clone();
             let key = key_obj.key;
-            let level = args.compression_level.clone();
+            let options = options.clone();
 
             tasks.push(smol::spawn(async move {
                 let _permit = sem_clone.acquire().await;
@@ -304,7 +717,7 @@ async fn async_main() -> anyhow::Result<()> {
                         None,
                         out_dir_clone.as_deref(),
                         key,
-                        level,
+                        &options,
                         &pb,
                     )
                     .await
@@ -314,6 +727,7 @@ async fn async_main() -> anyhow::Result<()> {
                         None,
                         out_dir_clone.as_deref(),
                         key,
+                        &options,
                         &pb,
                     )
                     .await
@@ -370,8 +784,8 @@ async fn async_main() -> anyhow::Result<()> {
         );
         pb.enable_steady_tick(std::time::Duration::from_millis(100));
 
-        let output_file = if args.outfile.is_some() {
-            args.outfile.unwrap()
+        let output_file = if let Some(outfile) = args.outfile {
+            outfile
         } else {
             format!("{}_encrypted.png", input_file.trim_end_matches(".png"))
         };
@@ -381,7 +795,7 @@ async fn async_main() -> anyhow::Result<()> {
             Some(output_file),
             None,
             key_obj.key,
-            args.compression_level,
+            &options,
             &pb,
         )
         .await?;
@@ -407,8 +821,8 @@ async fn async_main() -> anyhow::Result<()> {
         );
         pb.enable_steady_tick(std::time::Duration::from_millis(100));
 
-        let output_file = if args.outfile.is_some() {
-            args.outfile.unwrap()
+        let output_file = if let Some(outfile) = args.outfile {
+            outfile
         } else {
             format!("{}_decrypted.png", input_file.trim_end_matches(".png"))
         };
@@ -418,6 +832,7 @@ async fn async_main() -> anyhow::Result<()> {
             Some(output_file),
             None,
             key_obj.key,
+            &options,
             &pb,
         )
         .await?;
@@ -437,6 +852,31 @@ fn main() -> anyhow::Result<()> {
 mod tests {
     use super::*;
     use crate::png::{ImageType, PngInfo};
+    use crate::png::constants::{IDAT, IEND, IHDR, PNG_SIG};
+    use crate::png::write::write_chunk;
+    use crate::png::stream::{transcode, StreamingDecoder};
+    use flate2::write::ZlibEncoder;
+
+    // Builds a PNG around already filtered scanlines, `before_idat` chunks are written between IHDR and IDAT.
+    fn build_png(width: u32, height: u32, bit_depth: u8, color_type: u8, interlace: u8, raw: &[u8], before_idat: &[([u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&width.to_be_bytes());
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[bit_depth, color_type, 0, 0, interlace]);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(raw).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut bytes = PNG_SIG.to_vec();
+        write_chunk(&mut bytes, &IHDR, &ihdr, None).unwrap();
+        for (chunk_type, data) in before_idat {
+            write_chunk(&mut bytes, chunk_type, data, None).unwrap();
+        }
+        write_chunk(&mut bytes, &IDAT, &compressed, None).unwrap();
+        write_chunk(&mut bytes, &IEND, &[], None).unwrap();
+        bytes
+    }
 
     #[test]
     fn test_async_key_derivation_and_io() {
@@ -469,16 +909,16 @@ mod tests {
             let enc_path = "target/test_enc.png";
             let dec_path = "target/test_dec.png";
 
-            process_file_encrypt_async("d_file.png", Some(enc_path.to_string()), None, key_obj.key, CompressionLevel::Lossless, &pb)
+            process_file_encrypt_async("d_file.png", Some(enc_path.to_string()), None, key_obj.key, &ProcessOptions::default(), &pb)
                 .await
                 .unwrap();
 
-            process_file_decrypt_async(enc_path, Some(dec_path.to_string()), None, key_obj.key, &pb)
+            process_file_decrypt_async(enc_path, Some(dec_path.to_string()), None, key_obj.key, &ProcessOptions::default(), &pb)
                 .await
                 .unwrap();
 
-            let orig = DecodedPng::read_from_file_async("d_file.png", None, &pb).await.unwrap();
-            let dec = DecodedPng::read_from_file_async(dec_path, None, &pb).await.unwrap();
+            let orig = DecodedPng::read_from_file_async("d_file.png", None, DecodeOptions::default(), &pb).await.unwrap();
+            let dec = DecodedPng::read_from_file_async(dec_path, None, DecodeOptions::default(), &pb).await.unwrap();
 
             assert_eq!(orig.info.width, dec.info.width);
             assert_eq!(orig.info.height, dec.info.height);
@@ -509,8 +949,10 @@ mod tests {
                     color_type: 6,
                     interlace: 0,
                     image_type: ImageType::TruecolorAlpha,
+                    ..Default::default()
                 },
                 rgba,
+                ..Default::default()
             };
 
             let pb = ProgressBar::hidden();
@@ -521,15 +963,15 @@ mod tests {
                 let dec_path = format!("target/test_level_{:?}_dec.png", level);
 
                 test_image
-                    .save_optimized_async(&enc_path, level.clone(), Some(key), &pb)
+                    .save_optimized_async(&enc_path, level.clone(), Interlacing::None, Some(key), &pb)
                     .await
                     .unwrap();
 
-                process_file_decrypt_async(&enc_path, Some(dec_path.clone()), None, key, &pb)
+                process_file_decrypt_async(&enc_path, Some(dec_path.clone()), None, key, &ProcessOptions::default(), &pb)
                     .await
                     .unwrap();
 
-                let dec = DecodedPng::read_from_file_async(&dec_path, None, &pb).await.unwrap();
+                let dec = DecodedPng::read_from_file_async(&dec_path, None, DecodeOptions::default(), &pb).await.unwrap();
                 assert_eq!(dec.info.width, width);
                 assert_eq!(dec.info.height, height);
 
@@ -550,15 +992,1136 @@ mod tests {
             let key2 = [2u8; 32];
 
             let enc_path = "target/test_bad_key_enc.png";
-            process_file_encrypt_async("d_file.png", Some(enc_path.to_string()), None, key1, CompressionLevel::Lossless, &pb)
+            process_file_encrypt_async("d_file.png", Some(enc_path.to_string()), None, key1, &ProcessOptions::default(), &pb)
                 .await
                 .unwrap();
 
             // Attempt decrypting with wrong key
-            let res = DecodedPng::read_from_file_async(enc_path, Some(key2), &pb).await;
+            let res = DecodedPng::read_from_file_async(enc_path, Some(key2), DecodeOptions::default(), &pb).await;
             assert!(res.is_err());
 
             let _ = smol::fs::remove_file(enc_path).await;
         });
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_grayscale_sub_byte_decoding() {
+        let pb = ProgressBar::hidden();
+
+        // 5x2 at 2 bits per pixel, rows padded to 2 bytes: 0,1,2,3,0 / 3,3,2,1,0
+        let raw = [0u8, 0b00_01_10_11, 0b00_000000, 0, 0b11_11_10_01, 0b00_000000];
+        let bytes = build_png(5, 2, 2, 0, 0, &raw, &[]);
+        let image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+
+        let grays: Vec<u8> = image.rgba.chunks_exact(4).map(|p| p[0]).collect();
+        assert_eq!(grays, vec![0, 85, 170, 255, 0, 255, 255, 170, 85, 0]);
+        assert!(image.rgba.chunks_exact(4).all(|p| p[0] == p[1] && p[1] == p[2] && p[3] == 255));
+
+        // 16-bit samples keep the high byte
+        let raw = [0u8, 0xAB, 0xCD, 0x12, 0x34];
+        let bytes = build_png(2, 1, 16, 0, 0, &raw, &[]);
+        let image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        assert_eq!(image.rgba, vec![0xAB, 0xAB, 0xAB, 255, 0x12, 0x12, 0x12, 255]);
+    }
+
+    #[test]
+    fn test_indexed_color_with_transparency() {
+        use crate::png::constants::{PLTE, TRNS};
+        let pb = ProgressBar::hidden();
+
+        let plte = vec![255, 0, 0, 0, 255, 0, 0, 0, 255];
+        let trns = vec![0, 128];
+        // 4x1 at 2 bits per pixel: indices 0,1,2,1
+        let raw = [0u8, 0b00_01_10_01];
+        let bytes = build_png(4, 1, 2, 3, 0, &raw, &[(PLTE, plte.clone()), (TRNS, trns)]);
+        let image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        assert_eq!(image.rgba, vec![255, 0, 0, 0, 0, 255, 0, 128, 0, 0, 255, 255, 0, 255, 0, 128]);
+
+        // Index 3 does not exist in a three entry palette
+        let raw = [0u8, 0b11_00_00_00];
+        let bytes = build_png(4, 1, 2, 3, 0, &raw, &[(PLTE, plte)]);
+        assert!(DecodedPng::from_bytes(&bytes, None, &pb).is_err());
+
+        let bytes = build_png(4, 1, 2, 3, 0, &raw, &[]);
+        assert!(DecodedPng::from_bytes(&bytes, None, &pb).is_err());
+    }
+
+    #[test]
+    fn test_grayscale_alpha_roundtrip() {
+        let pb = ProgressBar::hidden();
+
+        // 16-bit gray + alpha decodes to the high bytes
+        let raw = [0u8, 0x80, 0x01, 0x40, 0x02];
+        let bytes = build_png(1, 1, 16, 4, 0, &raw, &[]);
+        let image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        assert_eq!(image.rgba, vec![0x80, 0x80, 0x80, 0x40]);
+
+        let rgba: Vec<u8> = (0..64u8).flat_map(|i| [i * 4, i * 4, i * 4, 255 - i]).collect();
+        let image = DecodedPng {
+            info: PngInfo { width: 8, height: 8, bit_depth: 8, color_type: 6, image_type: ImageType::TruecolorAlpha, ..Default::default() },
+            rgba,
+            ..Default::default()
+        };
+        let encoded = image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap();
+        assert_eq!(encoded[25], 4);
+
+        let decoded = DecodedPng::from_bytes(&encoded, None, &pb).unwrap();
+        assert_eq!(decoded.info.image_type, ImageType::GrayscaleAlpha);
+        assert_eq!(decoded.rgba, image.rgba);
+    }
+
+    #[test]
+    fn test_16_bit_precision_roundtrip() {
+        let pb = ProgressBar::hidden();
+
+        // 2x2 RGB at 16 bits, low bytes carry information that an 8-bit buffer would lose
+        let mut raw = Vec::new();
+        for row in [[0x1234u16, 0x5678, 0x9ABC, 0xFFFF, 0x0001, 0x8000], [0x0F0F, 0xF0F0, 0x00FF, 0xFF00, 0x1111, 0x2222]] {
+            raw.push(0);
+            raw.extend(row.iter().flat_map(|sample| sample.to_be_bytes()));
+        }
+        let bytes = build_png(2, 2, 16, 2, 0, &raw, &[]);
+        let image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+
+        let rgba16 = image.rgba16.clone().unwrap();
+        assert_eq!(&rgba16[..8], &[0x1234, 0x5678, 0x9ABC, 0xFFFF, 0xFFFF, 0x0001, 0x8000, 0xFFFF]);
+        assert_eq!(&image.rgba[..4], &[0x12, 0x56, 0x9A, 0xFF]);
+
+        let encoded = image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap();
+        assert_eq!(&encoded[24..26], &[16, 2]);
+        let decoded = DecodedPng::from_bytes(&encoded, None, &pb).unwrap();
+        assert_eq!(decoded.rgba16, Some(rgba16));
+
+        // Quantized output drops to 8 bits
+        let encoded = image.encode_optimized(CompressionLevel::Balanced, Interlacing::None, None, &pb).unwrap();
+        assert_eq!(encoded[24], 8);
+    }
+
+    #[test]
+    fn test_adam7_interlaced_decoding() {
+        let pb = ProgressBar::hidden();
+
+        // 3x3 gray image with value 10 * (y * 3 + x), passes 2 and 3 are empty for this size
+        let raw = [
+            0, 0,           // pass 1: (0,0)
+            0, 20,          // pass 4: (2,0)
+            0, 60, 80,      // pass 5: (0,2) (2,2)
+            0, 10, 2, 60,   // pass 6: (1,0) then (1,2) filtered Up against the previous pass row
+            0, 30, 40, 50,  // pass 7: row 1
+        ];
+        let bytes = build_png(3, 3, 8, 0, 1, &raw, &[]);
+        let image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+
+        let grays: Vec<u8> = image.rgba.chunks_exact(4).map(|p| p[0]).collect();
+        assert_eq!(grays, vec![0, 10, 20, 30, 40, 50, 60, 70, 80]);
+    }
+
+    #[test]
+    fn test_adam7_interlaced_encoding() {
+        let pb = ProgressBar::hidden();
+
+        let (width, height) = (13u32, 11u32);
+        let rgba: Vec<u8> = (0..width * height).flat_map(|i| [(i * 7) as u8, (i * 3) as u8, (i * 11) as u8, 255]).collect();
+        let image = DecodedPng {
+            info: PngInfo { width, height, bit_depth: 8, color_type: 6, image_type: ImageType::TruecolorAlpha, ..Default::default() },
+            rgba,
+            ..Default::default()
+        };
+
+        let interlaced = image.encode_optimized(CompressionLevel::Lossless, Interlacing::Adam7, None, &pb).unwrap();
+        assert_eq!(interlaced[28], 1);
+        let decoded = DecodedPng::from_bytes(&interlaced, None, &pb).unwrap();
+        assert_eq!(decoded.rgba, image.rgba);
+
+        let plain = image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap();
+        let auto = image.encode_optimized(CompressionLevel::Lossless, Interlacing::Auto, None, &pb).unwrap();
+        assert_eq!(auto.len(), plain.len().min(interlaced.len()));
+    }
+
+    #[test]
+    fn test_crc_and_adler32_verification() {
+        let pb = ProgressBar::hidden();
+        let lenient = DecodeOptions { crc_mode: CrcMode::Lenient, ..Default::default() };
+
+        let raw = [0u8, 10, 20, 30];
+        let bytes = build_png(3, 1, 8, 0, 0, &raw, &[]);
+
+        // Flip a bit in the IHDR CRC (signature 8 + length 4 + type 4 + data 13)
+        let mut bad_crc = bytes.clone();
+        bad_crc[8 + 4 + 4 + 13] ^= 1;
+        assert!(DecodedPng::from_bytes(&bad_crc, None, &pb).is_err());
+        let image = DecodedPng::from_bytes_with_options(&bad_crc, None, &lenient, &pb).unwrap();
+        assert_eq!(image.report.crc_mismatches.len(), 1);
+        assert_eq!(&image.report.crc_mismatches[0].chunk_type, b"IHDR");
+        assert_eq!(image.report.crc_mismatches[0].offset, 8);
+
+        // Corrupt the Adler-32 trailer, then rewrite the IDAT CRC so only the zlib check fails
+        let idat_start = 8 + 25;
+        let idat_length = u32::from_be_bytes(bytes[idat_start..idat_start + 4].try_into().unwrap()) as usize;
+        let mut bad_adler = bytes.clone();
+        bad_adler[idat_start + 8 + idat_length - 1] ^= 1;
+        let crc = crc32fast::hash(&bad_adler[idat_start + 4..idat_start + 8 + idat_length]);
+        bad_adler[idat_start + 8 + idat_length..idat_start + 12 + idat_length].copy_from_slice(&crc.to_be_bytes());
+        assert!(DecodedPng::from_bytes(&bad_adler, None, &pb).is_err());
+        let image = DecodedPng::from_bytes_with_options(&bad_adler, None, &lenient, &pb).unwrap();
+        assert!(image.report.crc_mismatches.is_empty());
+        assert!(image.report.adler32_mismatch.is_some());
+        assert_eq!(image.rgba[4..8], [20, 20, 20, 255]);
+    }
+
+    #[test]
+    fn test_chunk_ordering_is_enforced() {
+        let pb = ProgressBar::hidden();
+        let png = build_png(2, 1, 8, 0, 0, &[0u8, 1, 2], &[]);
+
+        // Split the valid file back into its chunks so they can be rearranged
+        let mut chunks = Vec::new();
+        let mut offset = 8;
+        while offset < png.len() {
+            let length = u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap()) as usize;
+            let chunk_type: [u8; 4] = png[offset + 4..offset + 8].try_into().unwrap();
+            chunks.push((chunk_type, png[offset + 8..offset + 8 + length].to_vec()));
+            offset += 12 + length;
+        }
+        let (ihdr, idat, iend) = (chunks[0].clone(), chunks[1].clone(), chunks[2].clone());
+        let assemble = |chunks: &[([u8; 4], Vec<u8>)]| {
+            let mut bytes = PNG_SIG.to_vec();
+            for (chunk_type, data) in chunks {
+                write_chunk(&mut bytes, chunk_type, data, None).unwrap();
+            }
+            bytes
+        };
+
+        assert!(DecodedPng::from_bytes(&assemble(&[ihdr.clone(), idat.clone(), iend.clone()]), None, &pb).is_ok());
+
+        let (first_half, second_half) = idat.1.split_at(idat.1.len() / 2);
+        let split = [ihdr.clone(), (IDAT, first_half.to_vec()), (IDAT, second_half.to_vec()), iend.clone()];
+        assert!(DecodedPng::from_bytes(&assemble(&split), None, &pb).is_ok());
+
+        let rejected = [
+            vec![idat.clone(), ihdr.clone(), iend.clone()],
+            vec![ihdr.clone(), ihdr.clone(), idat.clone(), iend.clone()],
+            vec![ihdr.clone(), (IDAT, first_half.to_vec()), (*b"tEXt", b"a\0b".to_vec()), (IDAT, second_half.to_vec()), iend.clone()],
+            vec![ihdr.clone(), (*b"ABCD", vec![]), idat.clone(), iend.clone()],
+            vec![ihdr.clone(), iend.clone()],
+            vec![ihdr.clone(), idat.clone()],
+            vec![],
+        ];
+        for chunks in rejected {
+            assert!(DecodedPng::from_bytes(&assemble(&chunks), None, &pb).is_err());
+        }
+
+        // Misplaced ancillary chunks are ignored and reported, like libpng does
+        let late_gamma = [ihdr.clone(), idat.clone(), (*b"gAMA", vec![0, 0, 0xB1, 0x8F]), iend.clone()];
+        let late_gamma = assemble(&late_gamma);
+        let image = DecodedPng::from_bytes(&late_gamma, None, &pb).unwrap();
+        assert_eq!(image.info.gamma, None);
+        assert_eq!(image.report.misplaced_chunks.len(), 1);
+        let mut decoder = StreamingDecoder::new(&late_gamma[..], None, DecodeOptions::default()).unwrap();
+        while decoder.next_row().unwrap().is_some() {}
+        assert_eq!(decoder.report().misplaced_chunks.len(), 1);
+
+        // Unknown ancillary chunks are skipped
+        let ancillary = [ihdr, (*b"abCD", vec![1, 2, 3]), idat, iend];
+        assert!(DecodedPng::from_bytes(&assemble(&ancillary), None, &pb).is_ok());
+    }
+
+    #[test]
+    fn test_decoder_errors_are_typed() {
+        use crate::png::error::PngError;
+        let pb = ProgressBar::hidden();
+
+        let raw = [0u8, 1, 2];
+        let bytes = build_png(2, 1, 8, 0, 0, &raw, &[]);
+
+        let mut not_png = bytes.clone();
+        not_png[1] = b'J';
+        assert!(matches!(DecodedPng::from_bytes(&not_png, None, &pb), Err(PngError::BadSignature)));
+        assert!(matches!(DecodedPng::from_bytes(&bytes[..4], None, &pb), Err(PngError::BadSignature)));
+        assert!(matches!(DecodedPng::from_bytes(&bytes[..bytes.len() - 6], None, &pb), Err(PngError::Truncated(_))));
+
+        let mut bad_crc = bytes.clone();
+        bad_crc[29] ^= 0xFF;
+        assert!(matches!(DecodedPng::from_bytes(&bad_crc, None, &pb), Err(PngError::BadCrc(_))));
+
+        // Filter type 5 doesn't exist
+        let bytes = build_png(2, 1, 8, 0, 0, &[5u8, 1, 2], &[]);
+        assert!(matches!(DecodedPng::from_bytes(&bytes, None, &pb), Err(PngError::Malformed(_))));
+
+        let bytes = build_png(2, 1, 8, 0, 0, &[0u8, 1], &[]);
+        assert!(matches!(DecodedPng::from_bytes(&bytes, None, &pb), Err(PngError::Malformed(_))));
+
+        let image = DecodedPng::from_bytes(&build_png(2, 1, 8, 0, 0, &raw, &[]), None, &pb).unwrap();
+        let encrypted = image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, Some(&[1u8; 32]), &pb).unwrap();
+        assert!(matches!(DecodedPng::from_bytes(&encrypted, Some(&[2u8; 32]), &pb), Err(PngError::Decryption)));
+    }
+
+    #[test]
+    fn test_decoder_limits() {
+        use crate::png::Limits;
+        use crate::png::error::PngError;
+        let pb = ProgressBar::hidden();
+        let with_limits = |limits: Limits| DecodeOptions { limits, ..Default::default() };
+
+        let raw: Vec<u8> = (0..4).flat_map(|_| [0u8, 0, 0, 0, 0]).collect();
+        let bytes = build_png(4, 4, 8, 0, 0, &raw, &[]);
+        assert!(DecodedPng::from_bytes(&bytes, None, &pb).is_ok());
+
+        for limits in [
+            Limits { max_width: 3, ..Default::default() },
+            Limits { max_height: 3, ..Default::default() },
+            Limits { max_pixels: 15, ..Default::default() },
+            Limits { max_chunk_size: 12, ..Default::default() },
+            Limits { max_decompressed_bytes: 19, ..Default::default() },
+            Limits { max_chunks: 2, ..Default::default() },
+        ] {
+            let result = DecodedPng::from_bytes_with_options(&bytes, None, &with_limits(limits), &pb);
+            assert!(matches!(result, Err(PngError::LimitExceeded(_))), "{:?}", limits);
+        }
+
+        // A chunk claiming 2 GiB in a tiny file is rejected without allocating it
+        let mut huge = bytes[..33].to_vec();
+        huge.extend_from_slice(&0x7FFF_FFFFu32.to_be_bytes());
+        huge.extend_from_slice(b"IDAT");
+        let result = DecodedPng::from_bytes_with_options(&huge, None, &with_limits(Limits { max_chunk_size: u32::MAX, ..Default::default() }), &pb);
+        assert!(matches!(result, Err(PngError::Truncated(_))));
+
+        // Image data that inflates past the size IHDR declares is cut off there
+        let bomb = build_png(4, 4, 8, 0, 0, &vec![0u8; 1 << 20], &[]);
+        assert!(matches!(DecodedPng::from_bytes(&bomb, None, &pb), Err(PngError::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn test_streaming_decoder_matches_whole_file_decoding() {
+        use crate::png::error::PngError;
+        let pb = ProgressBar::hidden();
+        let key = [7u8; 32];
+
+        let (width, height) = (13u32, 11u32);
+        let rgba: Vec<u8> = (0..width * height).flat_map(|i| [(i * 7) as u8, (i * 3) as u8, (i * 11) as u8, (i * 5) as u8]).collect();
+        let image = DecodedPng {
+            info: PngInfo { width, height, bit_depth: 8, color_type: 6, image_type: ImageType::TruecolorAlpha, ..Default::default() },
+            rgba,
+            ..Default::default()
+        };
+
+        let stream_decode = |bytes: &[u8], key: Option<&[u8; 32]>| {
+            let decoder = StreamingDecoder::new(std::io::Cursor::new(bytes), key, DecodeOptions::default()).unwrap();
+            let mut rgba = vec![0u8; (width * height * 4) as usize];
+            decoder.for_each_row(|info, row| {
+                for (px, pixel) in row.to_rgba(info)?.chunks_exact(4).enumerate() {
+                    let index = row.pass.image_index(px, row.y, width as usize) * 4;
+                    rgba[index..index + 4].copy_from_slice(pixel);
+                }
+                Ok(())
+            }).unwrap();
+            rgba
+        };
+
+        for interlacing in [Interlacing::None, Interlacing::Adam7] {
+            let bytes = image.encode_optimized(CompressionLevel::Lossless, interlacing, None, &pb).unwrap();
+            assert_eq!(stream_decode(&bytes, None), image.rgba);
+
+            let encrypted = image.encode_optimized(CompressionLevel::Lossless, interlacing, Some(&key), &pb).unwrap();
+            assert_eq!(stream_decode(&encrypted, Some(&key)), image.rgba);
+        }
+
+        // Image data split over many tiny IDAT chunks
+        let bytes = image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap();
+        let idat_length = u32::from_be_bytes(bytes[33..37].try_into().unwrap()) as usize;
+        let mut split = bytes[..33].to_vec();
+        for piece in bytes[41..41 + idat_length].chunks(5) {
+            write_chunk(&mut split, &IDAT, piece, None).unwrap();
+        }
+        write_chunk(&mut split, &IEND, &[], None).unwrap();
+        assert_eq!(stream_decode(&split, None), image.rgba);
+
+        // A stream cut short fails once the rows run out
+        let truncated = &split[..split.len() - 40];
+        let mut decoder = StreamingDecoder::new(std::io::Cursor::new(truncated), None, DecodeOptions::default()).unwrap();
+        let result = loop {
+            match decoder.next_row() {
+                Ok(Some(_)) => continue,
+                other => break other.map(|_| ()),
+            }
+        };
+        assert!(matches!(result, Err(PngError::Truncated(_))));
+
+        // Decoding a whole image through it gives what the whole file decoder gives, metadata included
+        let palette = [255u8, 0, 0, 0, 255, 0, 0, 0, 255, 9, 9, 9].to_vec();
+        let mut indexed = build_png(4, 2, 2, 3, 0, &[0, 0b0001_1011, 0, 0b1110_0100], &[
+            (crate::png::constants::GAMA, 45455u32.to_be_bytes().to_vec()),
+            (*b"prVT", vec![1, 2]),
+            (crate::png::constants::PLTE, palette),
+            (crate::png::constants::TRNS, vec![0]),
+            (crate::png::constants::TEXT, b"Author\0Greg".to_vec()),
+        ]);
+        let iend = indexed.split_off(indexed.len() - 12);
+        write_chunk(&mut indexed, &crate::png::constants::TEXT, b"Comment\0late", None).unwrap();
+        write_chunk(&mut indexed, b"prVT", &[3], None).unwrap();
+        indexed.extend_from_slice(&iend);
+        let deep = build_png(2, 1, 16, 0, 0, &[0, 0x12, 0x34, 0xff, 0x00], &[]);
+        let interlaced = build_png(1, 1, 8, 0, 1, &[0, 77], &[]);
+        let encrypted = image.encode_optimized(CompressionLevel::Lossless, Interlacing::Adam7, Some(&key), &pb).unwrap();
+        for (bytes, key) in [(&indexed, None), (&deep, None), (&interlaced, None), (&encrypted, Some(&key))] {
+            let whole = DecodedPng::from_bytes(bytes, key, &pb).unwrap();
+            let streamed = StreamingDecoder::new(bytes.as_slice(), key, DecodeOptions::default()).unwrap().decode(&pb).unwrap();
+            assert_eq!((&streamed.rgba, &streamed.rgba16, &streamed.chunks, &streamed.text), (&whole.rgba, &whole.rgba16, &whole.chunks, &whole.text));
+            assert_eq!(format!("{:?}", streamed.info), format!("{:?}", whole.info));
+            if bytes == &indexed {
+                assert_eq!((streamed.chunks.len(), streamed.text.len()), (2, 2));
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming_encoder_roundtrip() {
+        use crate::png::stream::StreamingEncoder;
+        let pb = ProgressBar::hidden();
+        let key = [9u8; 32];
+
+        // Noisy enough that the image data needs several IDAT chunks
+        let (width, height) = (200u32, 150u32);
+        let mut state = 0x1234_5678u32;
+        let rgba: Vec<u8> = (0..width * height * 4).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+        let info = PngInfo { width, height, bit_depth: 8, color_type: 6, image_type: ImageType::TruecolorAlpha, ..Default::default() };
+        let image = DecodedPng { info: info.clone(), rgba, ..Default::default() };
+
+        for (interlace, level, key) in [
+            (0u8, CompressionLevel::Lossless, None),
+            (1u8, CompressionLevel::Balanced, Some(&key)),
+        ] {
+            let image = DecodedPng { info: PngInfo { interlace, ..info.clone() }, ..image.clone() };
+            let mut encoder = StreamingEncoder::new(Vec::new(), &image, level, key).unwrap();
+            while let Some(pass) = encoder.next_pass().copied() {
+                for py in 0..pass.height {
+                    let row: Vec<u8> = (0..pass.width)
+                        .flat_map(|px| {
+                            let index = pass.image_index(px, py, width as usize) * 4;
+                            image.rgba[index..index + 4].to_vec()
+                        })
+                        .collect();
+                    encoder.write_row(&row).unwrap();
+                }
+            }
+            let bytes = encoder.finish(&image).unwrap();
+
+            assert!(bytes.windows(4).filter(|window| *window == IDAT).count() > 1);
+            assert_eq!(bytes[28], interlace);
+            let decoded = DecodedPng::from_bytes(&bytes, key, &pb).unwrap();
+            assert_eq!(decoded.rgba, image.rgba);
+        }
+
+        // Stream decoded rows straight back into an encoder, keeping the palette, sub-byte packing and every
+        // chunk where the regular encoder would put it
+        let palette = [255u8, 0, 0, 0, 255, 0, 0, 0, 255, 9, 9, 9].to_vec();
+        let mut source = build_png(4, 2, 2, 3, 0, &[0, 0b0001_1011, 0, 0b1110_0100], &[
+            (crate::png::constants::GAMA, 45455u32.to_be_bytes().to_vec()),
+            (*b"prVt", vec![1, 2]),
+            (crate::png::constants::PLTE, palette),
+            (crate::png::constants::TRNS, vec![0]),
+            (crate::png::constants::TEXT, b"Author\0Greg".to_vec()),
+        ]);
+        let iend = source.split_off(source.len() - 12);
+        write_chunk(&mut source, &crate::png::constants::TEXT, b"Comment\0late", None).unwrap();
+        write_chunk(&mut source, b"prVt", &[3], None).unwrap();
+        source.extend_from_slice(&iend);
+        let whole = DecodedPng::from_bytes(&source, None, &pb).unwrap();
+        for (level, key) in [(CompressionLevel::Maximum, None), (CompressionLevel::Lossless, Some(&key))] {
+            let decoder = StreamingDecoder::new(std::io::Cursor::new(&source), None, DecodeOptions::default()).unwrap();
+            let metadata = decoder.metadata();
+            let (bytes, _) = transcode(decoder, metadata, Vec::new(), level, key).unwrap();
+            assert_eq!(&bytes[24..26], &[2, 3]);
+            let streamed = DecodedPng::from_bytes(&bytes, key, &pb).unwrap();
+            assert_eq!((&streamed.rgba, &streamed.chunks, &streamed.text), (&whole.rgba, &whole.chunks, &whole.text));
+            assert_eq!(format!("{:?}", streamed.info), format!("{:?}", whole.info));
+        }
+
+        // Finishing early is an error
+        let encoder = StreamingEncoder::new(Vec::new(), &image, CompressionLevel::Lossless, None).unwrap();
+        assert!(encoder.finish(&image).is_err());
+
+        // Large files are encrypted and decrypted through it, a zero threshold makes every file large
+        let options = ProcessOptions { physical: Some(PhysicalDimensions::from_dpi(300.0)), ..Default::default() };
+        let (source_path, enc_path, dec_path) = ("target/test_stream_src.png", "target/test_stream_enc.png", "target/test_stream_dec.png");
+        std::fs::write(source_path, &source).unwrap();
+        smol::block_on(async {
+            assert!(stream_file(source_path, enc_path, None, Some(key), CompressionLevel::Lossless, &options, 0, &pb).await.unwrap());
+            assert!(stream_file(enc_path, dec_path, Some(key), None, CompressionLevel::Lossless, &ProcessOptions::default(), 0, &pb).await.unwrap());
+            // Pixel edits and smaller files take the regular path
+            let auto_orient = ProcessOptions { auto_orient: true, ..Default::default() };
+            assert!(!stream_file(source_path, dec_path, None, None, CompressionLevel::Lossless, &auto_orient, 0, &pb).await.unwrap());
+            assert!(!stream_file(source_path, dec_path, None, None, CompressionLevel::Lossless, &options, STREAMING_THRESHOLD, &pb).await.unwrap());
+        });
+        assert!(DecodedPng::from_bytes(&std::fs::read(enc_path).unwrap(), None, &pb).is_err());
+        let decrypted = DecodedPng::from_bytes(&std::fs::read(dec_path).unwrap(), None, &pb).unwrap();
+        assert_eq!((&decrypted.rgba, &decrypted.chunks, &decrypted.text), (&whole.rgba, &whole.chunks, &whole.text));
+        assert_eq!(decrypted.info.physical.unwrap().x, 11811);
+        for path in [source_path, enc_path, dec_path] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn test_recovery_of_truncated_files() {
+        use crate::png::error::PngError;
+        use crate::png::Damage;
+        let pb = ProgressBar::hidden();
+        let recover = DecodeOptions { recover: true, ..Default::default() };
+
+        // 4x64 gray image, stored uncompressed so cutting the file maps cleanly onto rows
+        let raw: Vec<u8> = (0..64u8).flat_map(|y| [0, y, y, y, y]).collect();
+        let mut compressed = Vec::new();
+        let mut encoder = ZlibEncoder::new(&mut compressed, flate2::Compression::none());
+        encoder.write_all(&raw).unwrap();
+        encoder.finish().unwrap();
+        let mut bytes = build_png(4, 64, 8, 0, 0, &[], &[]);
+        bytes.truncate(33);
+        write_chunk(&mut bytes, &IDAT, &compressed, None).unwrap();
+
+        // No IEND and the file stops halfway through the image data
+        let cut = &bytes[..33 + 8 + 2 + 5 + 40 * 5];
+        assert!(matches!(DecodedPng::from_bytes(cut, None, &pb), Err(PngError::Truncated(_))));
+        let image = DecodedPng::from_bytes_with_options(cut, None, &recover, &pb).unwrap();
+        assert_eq!(image.rgba.len(), 4 * 64 * 4);
+        assert_eq!(image.rgba[39 * 16..39 * 16 + 4], [39, 39, 39, 255]);
+        assert_eq!(image.rgba[40 * 16..], vec![0u8; 24 * 16]);
+
+        let damage = &image.report.damage;
+        assert!(matches!(damage[0], Damage::TruncatedChunk { chunk_type: IDAT, .. }));
+        assert!(damage.contains(&Damage::MissingIend));
+        assert!(matches!(damage.last(), Some(Damage::IncompleteImageData { rows: 40, total_rows: 64, .. })));
+        assert_eq!(image.report.messages().len(), 3);
+
+        // A complete file with a bad IDAT CRC decodes fully and only reports the CRC
+        write_chunk(&mut bytes, &IEND, &[], None).unwrap();
+        bytes[33 + 8 + compressed.len()] ^= 1;
+        let image = DecodedPng::from_bytes_with_options(&bytes, None, &recover, &pb).unwrap();
+        assert_eq!(image.report.crc_mismatches.len(), 1);
+        assert!(image.report.damage.is_empty());
+        assert_eq!(image.rgba[63 * 16..63 * 16 + 4], [63, 63, 63, 255]);
+
+        // A flipped bit turns tEXt into an unknown critical chunk, recovery skips it and decodes the rest
+        let mut bytes = build_png(4, 64, 8, 0, 0, &raw, &[(*b"tEXt", b"a\0b".to_vec())]);
+        bytes[33 + 4] ^= 0x20;
+        assert!(matches!(DecodedPng::from_bytes(&bytes, None, &pb), Err(PngError::BadCrc(_))));
+        let image = DecodedPng::from_bytes_with_options(&bytes, None, &recover, &pb).unwrap();
+        assert!(matches!(&image.report.damage[..], [Damage::SkippedChunk { chunk_type, offset: 33, .. }] if chunk_type == b"TEXt"));
+        assert_eq!(image.rgba[63 * 16..63 * 16 + 4], [63, 63, 63, 255]);
+    }
+
+    #[test]
+    fn test_ancillary_chunks_are_preserved() {
+        use crate::png::constants::{CLLI, EXIF};
+        use crate::png::{AncillaryChunk, ChunkPosition};
+        let pb = ProgressBar::hidden();
+
+        // cLLI is too short to parse, so it's carried through raw like an unknown chunk
+        let raw = [0u8, 10, 20, 0, 30, 40];
+        let mut bytes = build_png(2, 2, 8, 0, 0, &raw, &[
+            (CLLI, vec![0, 0, 3, 232]),
+            (*b"prVt", b"safe".to_vec()),
+            (*b"prVT", b"unsafe".to_vec()),
+        ]);
+        let iend = bytes.split_off(bytes.len() - 12);
+        write_chunk(&mut bytes, b"laTe", b"after", None).unwrap();
+        bytes.extend_from_slice(&iend);
+
+        let image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        let chunk = |chunk_type: &[u8; 4], data: &[u8], position| AncillaryChunk { chunk_type: *chunk_type, data: data.to_vec(), position };
+        assert_eq!(image.chunks, vec![
+            chunk(&CLLI, &[0, 0, 3, 232], ChunkPosition::BeforePlte),
+            chunk(b"prVt", b"safe", ChunkPosition::BeforePlte),
+            chunk(b"prVT", b"unsafe", ChunkPosition::BeforePlte),
+            chunk(b"laTe", b"after", ChunkPosition::AfterIdat),
+        ]);
+
+        // Lossless output keeps the same gray format, so every chunk comes back where it was
+        let lossless = image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, Some(&[1u8; 32]), &pb).unwrap();
+        let reread = DecodedPng::from_bytes(&lossless, Some(&[1u8; 32]), &pb).unwrap();
+        assert_eq!(reread.chunks, image.chunks);
+        // Their payloads are encrypted along with the image data
+        for payload in [b"safe".as_slice(), b"unsafe", b"after"] {
+            assert!(!lossless.windows(payload.len()).any(|window| window == payload));
+        }
+
+        // Quantizing changes the pixels, the unknown unsafe chunk goes but the color space and safe chunks stay
+        let lossy = image.encode_optimized(CompressionLevel::Balanced, Interlacing::None, None, &pb).unwrap();
+        let kept: Vec<[u8; 4]> = DecodedPng::from_bytes(&lossy, None, &pb).unwrap().chunks.iter().map(|c| c.chunk_type).collect();
+        assert_eq!(kept, vec![CLLI, *b"prVt", *b"laTe"]);
+
+        // Rotating by the EXIF orientation (6) rewrites the pixels, so even lossless output drops the unsafe chunk
+        let exif = [b"II*\0".as_slice(), &[8, 0, 0, 0, 1, 0, 0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0]].concat();
+        let bytes = build_png(2, 2, 8, 0, 0, &raw, &[(EXIF, exif), (*b"prVt", b"safe".to_vec()), (*b"prVT", b"unsafe".to_vec())]);
+        let mut rotated = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        rotated.apply_orientation();
+        assert!(rotated.pixels_modified);
+        let lossless = rotated.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap();
+        let kept: Vec<[u8; 4]> = DecodedPng::from_bytes(&lossless, None, &pb).unwrap().chunks.iter().map(|c| c.chunk_type).collect();
+        assert_eq!(kept, vec![*b"prVt"]);
+    }
+
+    #[test]
+    fn test_text_chunks_roundtrip() {
+        use crate::png::constants::{ITXT, TEXT, ZTXT};
+        use crate::png::ChunkPosition;
+        use crate::png::text::{InternationalText, TextChunk};
+        let pb = ProgressBar::hidden();
+
+        let mut ztxt = b"Comment\0\0".to_vec();
+        let mut encoder = ZlibEncoder::new(&mut ztxt, flate2::Compression::default());
+        encoder.write_all("caf\u{e9}".chars().map(|c| c as u8).collect::<Vec<u8>>().as_slice()).unwrap();
+        encoder.finish().unwrap();
+        let itxt = "Title\0\0\0ja\0\u{984c}\u{540d}\0\u{5bcc}\u{58eb}\u{5c71}".as_bytes().to_vec();
+
+        let bytes = build_png(1, 1, 8, 0, 0, &[0, 0], &[
+            (TEXT, b"Author\0Greg".to_vec()),
+            (ZTXT, ztxt),
+            (ITXT, itxt),
+            (TEXT, b"bad  keyword\0kept raw".to_vec()),
+        ]);
+        let mut image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+
+        assert_eq!(image.text("Author"), Some("Greg"));
+        assert_eq!(image.text("Comment"), Some("caf\u{e9}"));
+        assert!(image.text[1].compressed);
+        assert_eq!(image.text[2], TextChunk {
+            keyword: "Title".to_string(),
+            text: "\u{5bcc}\u{58eb}\u{5c71}".to_string(),
+            compressed: false,
+            international: Some(InternationalText { language_tag: "ja".to_string(), translated_keyword: "\u{984c}\u{540d}".to_string() }),
+            position: ChunkPosition::BeforePlte,
+        });
+        assert_eq!(image.chunks.len(), 1);
+
+        image.remove_text("Author");
+        image.set_text("Source", "https://example.com/\u{1f5bc}").unwrap();
+        image.set_text("License", &"MIT ".repeat(500)).unwrap();
+        assert!(image.set_text(" Padded", "x").is_err());
+
+        let encoded = image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap();
+        let reread = DecodedPng::from_bytes(&encoded, None, &pb).unwrap();
+        assert_eq!(reread.text, image.text);
+        let types: Vec<[u8; 4]> = reread.text.iter().map(|t| t.chunk_type()).collect();
+        assert_eq!(types, vec![ZTXT, ITXT, ITXT, ZTXT]);
+
+        // Text after the image data stays there
+        let mut bytes = build_png(1, 1, 8, 0, 0, &[0, 0], &[]);
+        let iend = bytes.split_off(bytes.len() - 12);
+        write_chunk(&mut bytes, &TEXT, b"Comment\0late", None).unwrap();
+        bytes.extend_from_slice(&iend);
+        let image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        assert_eq!(image.text[0].position, ChunkPosition::AfterIdat);
+        let encoded = image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap();
+        let find = |chunk_type: &[u8]| encoded.windows(4).position(|w| w == chunk_type).unwrap();
+        assert!(find(&TEXT) > find(b"IDAT"));
+        assert_eq!(DecodedPng::from_bytes(&encoded, None, &pb).unwrap().text, image.text);
+    }
+
+    #[test]
+    fn test_color_space_chunks_and_srgb_normalization() {
+        use crate::png::color::{conversion_to_srgb, SRGB_CHROMATICITIES};
+        use crate::png::constants::{CHRM, GAMA, SRGB};
+        use crate::png::{Chromaticities, RenderingIntent};
+        let pb = ProgressBar::hidden();
+        let chrm = |c: &Chromaticities| [c.white, c.red, c.green, c.blue].iter().flat_map(|&(x, y)| [x, y]).flat_map(u32::to_be_bytes).collect::<Vec<u8>>();
+
+        // Linear gray is brightened by the sRGB curve, 128 -> 188
+        let bytes = build_png(2, 1, 8, 0, 0, &[0, 128, 255], &[(GAMA, 100000u32.to_be_bytes().to_vec())]);
+        let mut image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        assert_eq!(image.info.gamma, Some(100000));
+        image.normalize_to_srgb().unwrap();
+        assert_eq!(image.rgba, vec![188, 188, 188, 255, 255, 255, 255, 255]);
+        assert_eq!((image.info.gamma, image.info.srgb), (None, Some(RenderingIntent::Perceptual)));
+
+        let encoded = image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap();
+        assert!(encoded.windows(4).any(|w| w == SRGB) && !encoded.windows(4).any(|w| w == GAMA));
+
+        // sRGB primaries convert to themselves
+        let identity = conversion_to_srgb(&SRGB_CHROMATICITIES).unwrap();
+        for (i, row) in identity.iter().enumerate() {
+            for (j, value) in row.iter().enumerate() {
+                assert!((value - if i == j { 1.0 } else { 0.0 }).abs() < 1e-9);
+            }
+        }
+
+        // Display P3 with the sRGB curve: colors get more saturated, white stays white
+        let p3 = Chromaticities { white: (31270, 32900), red: (68000, 32000), green: (26500, 69000), blue: (15000, 6000) };
+        let raw = [0u8, 200, 100, 50, 255, 255, 255];
+        let bytes = build_png(2, 1, 8, 2, 0, &raw, &[(CHRM, chrm(&p3))]);
+        let mut image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        assert_eq!(image.info.chromaticities, Some(p3));
+        image.normalize_to_srgb().unwrap();
+        assert!(image.rgba[0] > 200 && image.rgba[1] < 100);
+        assert_eq!(image.rgba[4..], [255, 255, 255, 255]);
+
+        // Already sRGB: pixels stay as they are and the redundant gAMA/cHRM go
+        let bytes = build_png(2, 1, 8, 2, 0, &raw, &[
+            (CHRM, chrm(&SRGB_CHROMATICITIES)),
+            (GAMA, 45455u32.to_be_bytes().to_vec()),
+            (SRGB, vec![1]),
+        ]);
+        let mut image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        let before = image.rgba.clone();
+        let reencoded = DecodedPng::from_bytes(&image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap(), None, &pb).unwrap();
+        assert_eq!((reencoded.info.gamma, reencoded.info.chromaticities, reencoded.info.srgb), (Some(45455), Some(SRGB_CHROMATICITIES), Some(RenderingIntent::RelativeColorimetric)));
+        image.normalize_to_srgb().unwrap();
+        assert_eq!(image.rgba, before);
+        assert_eq!((image.info.gamma, image.info.chromaticities), (None, None));
+    }
+
+    #[test]
+    fn test_icc_profile_roundtrip_and_conversion() {
+        use crate::png::constants::ICCP;
+        let pb = ProgressBar::hidden();
+
+        // Linear light RGB profile with the sRGB colorants, adapted to D50
+        let fixed = |v: f64| ((v * 65536.0).round() as i32).to_be_bytes();
+        let mut tags: Vec<([u8; 4], Vec<u8>)> = Vec::new();
+        for (tag, xyz) in [(b"rXYZ", [0.4361, 0.2225, 0.0139]), (b"gXYZ", [0.3851, 0.7169, 0.0971]), (b"bXYZ", [0.1431, 0.0606, 0.7141])] {
+            let mut data = b"XYZ \0\0\0\0".to_vec();
+            xyz.iter().for_each(|&v| data.extend_from_slice(&fixed(v)));
+            tags.push((*tag, data));
+        }
+        for tag in [b"rTRC", b"gTRC", b"bTRC"] {
+            let mut data = b"para\0\0\0\0\0\0\0\0".to_vec();
+            data.extend_from_slice(&fixed(1.0));
+            tags.push((*tag, data));
+        }
+        let mut profile = vec![0u8; 128];
+        profile[16..20].copy_from_slice(b"RGB ");
+        profile[20..24].copy_from_slice(b"XYZ ");
+        profile[36..40].copy_from_slice(b"acsp");
+        profile.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        let mut offset = 132 + tags.len() * 12;
+        for (tag, data) in &tags {
+            profile.extend_from_slice(tag);
+            profile.extend_from_slice(&(offset as u32).to_be_bytes());
+            profile.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            offset += data.len();
+        }
+        tags.iter().for_each(|(_, data)| profile.extend_from_slice(data));
+
+        let mut iccp = b"Linear sRGB\0\0".to_vec();
+        let mut encoder = ZlibEncoder::new(&mut iccp, flate2::Compression::default());
+        encoder.write_all(&profile).unwrap();
+        encoder.finish().unwrap();
+
+        let raw = [0u8, 128, 128, 128, 255, 0, 0, 128, 64, 32];
+        let bytes = build_png(3, 1, 8, 2, 0, &raw, &[(ICCP, iccp)]);
+        let mut image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        let embedded = image.info.icc_profile.clone().unwrap();
+        assert_eq!((embedded.name.as_str(), &embedded.data), ("Linear sRGB", &profile));
+        assert!(image.chunks.is_empty());
+
+        let encoded = image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap();
+        assert_eq!(DecodedPng::from_bytes(&encoded, None, &pb).unwrap().info.icc_profile, Some(embedded));
+
+        // Linear light is re-encoded with the sRGB curve, the primaries stay where they are
+        image.normalize_to_srgb().unwrap();
+        assert!(image.info.icc_profile.is_none());
+        let expected = [188u8, 188, 188, 255, 255, 0, 0, 255, 188, 137, 99, 255];
+        for (value, expected) in image.rgba.iter().zip(expected) {
+            assert!(value.abs_diff(expected) <= 1, "{:?}", image.rgba);
+        }
+    }
+
+    #[test]
+    fn test_physical_dimensions_roundtrip() {
+        use crate::png::constants::PHYS;
+        use crate::png::PhysicalUnit;
+        use crate::png::stream::{StreamingDecoder, StreamingEncoder};
+        let pb = ProgressBar::hidden();
+
+        // 3780 pixels per meter is 96 DPI
+        let phys = [3780u32.to_be_bytes(), 3780u32.to_be_bytes()].concat().into_iter().chain([1]).collect::<Vec<u8>>();
+        let bytes = build_png(1, 1, 8, 0, 0, &[0, 7], &[(PHYS, phys)]);
+        let mut image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        let physical = image.info.physical.unwrap();
+        assert_eq!(physical, PhysicalDimensions { x: 3780, y: 3780, unit: PhysicalUnit::Meter });
+        assert_eq!(physical.dpi().map(|(x, y)| (x.round(), y.round())), Some((96.0, 96.0)));
+        assert!(image.chunks.is_empty());
+        assert!(describe(&image).iter().any(|line| line.contains("96x96 DPI")));
+
+        // Kept on re-encode, and settable
+        let reencoded = DecodedPng::from_bytes(&image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap(), None, &pb).unwrap();
+        assert_eq!(reencoded.info.physical, Some(physical));
+        let options = ProcessOptions { physical: Some(PhysicalDimensions::from_dpi(300.0)), ..Default::default() };
+        smol::block_on(apply_edits(&mut image, &options)).unwrap();
+        assert_eq!(image.info.physical.unwrap().x, 11811);
+
+        // The streaming encoder and decoder carry it too
+        let mut encoder = StreamingEncoder::new(Vec::new(), &image, CompressionLevel::Balanced, None).unwrap();
+        encoder.write_row(&[7]).unwrap();
+        let streamed = encoder.finish(&image).unwrap();
+        let decoder = StreamingDecoder::new(streamed.as_slice(), None, DecodeOptions::default()).unwrap();
+        assert_eq!(decoder.info().physical, image.info.physical);
+
+        // An unknown unit is only an aspect ratio
+        let aspect = PhysicalDimensions { x: 2, y: 1, unit: PhysicalUnit::Unknown };
+        assert_eq!(aspect.dpi(), None);
+        let bytes = build_png(1, 1, 8, 0, 0, &[0, 7], &[(PHYS, aspect.to_bytes())]);
+        assert_eq!(DecodedPng::from_bytes(&bytes, None, &pb).unwrap().info.physical, Some(aspect));
+    }
+
+    #[test]
+    fn test_exif_orientation_and_redaction() {
+        use crate::png::constants::EXIF;
+        use crate::png::exif::Exif;
+        let pb = ProgressBar::hidden();
+
+        // Little endian TIFF: IFD0 at 8 with orientation and the Exif/GPS IFD pointers, the Exif IFD at 50
+        // with a serial number and version, the GPS IFD at 80 with a latitude, then the out of line values
+        let entry = |tag: u16, field_type: u16, count: u32, value: u32| [&tag.to_le_bytes()[..], &field_type.to_le_bytes(), &count.to_le_bytes(), &value.to_le_bytes()].concat();
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        tiff.extend(entry(0x0112, 3, 1, 6));
+        tiff.extend(entry(0x8769, 4, 1, 50));
+        tiff.extend(entry(0x8825, 4, 1, 80));
+        tiff.extend_from_slice(&[0; 4]);
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        tiff.extend(entry(0xA431, 2, 8, 98));
+        tiff.extend(entry(0x9000, 7, 4, u32::from_le_bytes(*b"0232")));
+        tiff.extend_from_slice(&[0; 4]);
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend(entry(0x0002, 5, 3, 106));
+        tiff.extend_from_slice(&[0; 4]);
+        tiff.extend_from_slice(b"SN12345\0");
+        let latitude: Vec<u8> = [51u32, 1, 30, 1, 0, 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+        tiff.extend_from_slice(&latitude);
+        assert_eq!(tiff.len(), 130);
+
+        let bytes = build_png(2, 1, 8, 2, 0, &[0, 255, 0, 0, 0, 0, 255], &[(EXIF, tiff.clone())]);
+        let mut image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        let exif = image.info.exif.as_ref().unwrap();
+        assert_eq!((exif.orientation(), exif.has_location()), (Some(6), true));
+        assert!(describe(&image).iter().any(|line| line.contains("has GPS location")));
+
+        // Rotated 90 degrees clockwise, the left pixel ends up on top
+        image.apply_orientation();
+        assert_eq!((image.info.width, image.info.height), (1, 2));
+        assert_eq!(image.rgba, vec![255, 0, 0, 255, 0, 0, 255, 255]);
+        assert_eq!(image.info.exif.as_ref().unwrap().orientation(), Some(1));
+
+        // GPS and the serial number are gone without a trace, everything else stays
+        let exif = image.info.exif.as_mut().unwrap();
+        assert_eq!(exif.redact(), 3);
+        assert!(!exif.has_location());
+        assert!(!exif.data.windows(7).any(|w| w == b"SN12345"));
+        assert!(!exif.data.windows(latitude.len()).any(|w| w == latitude));
+        assert!(exif.data.windows(4).any(|w| w == b"0232"));
+        assert_eq!(exif.data.len(), tiff.len());
+
+        let reencoded = DecodedPng::from_bytes(&image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap(), None, &pb).unwrap();
+        assert_eq!(reencoded.info.exif, image.info.exif);
+        assert!(reencoded.chunks.is_empty());
+
+        // A GPS pointer back at IFD0 or past the end only loses the pointer, IFD0 itself is left alone
+        for gps_offset in [8u32, 1000] {
+            let mut crafted = tiff.clone();
+            crafted[42..46].copy_from_slice(&gps_offset.to_le_bytes());
+            let mut exif = Exif::parse(&crafted).unwrap();
+            assert_eq!(exif.redact(), 2);
+            assert_eq!((exif.orientation(), exif.has_location()), (Some(6), false));
+            assert!(!exif.data.windows(7).any(|w| w == b"SN12345"));
+        }
+    }
+
+    #[test]
+    fn test_flatten_alpha_onto_background() {
+        use crate::png::constants::BKGD;
+        let pb = ProgressBar::hidden();
+
+        // Transparent red and half transparent white over a blue bKGD
+        let raw = [0u8, 255, 0, 0, 0, 255, 255, 255, 128];
+        let bytes = build_png(2, 1, 8, 6, 0, &raw, &[(BKGD, vec![0, 0, 0, 0, 0, 255])]);
+        let mut image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        assert_eq!(image.info.background, Some([0, 0, 65535]));
+        image.flatten_alpha(Some([255, 255, 255])).unwrap();
+        assert_eq!(image.rgba, vec![0, 0, 255, 255, 128, 128, 255, 255]);
+
+        // The alpha channel is gone from the output, bKGD is rewritten for the new color type
+        let reencoded = DecodedPng::from_bytes(&image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap(), None, &pb).unwrap();
+        assert_eq!((reencoded.info.color_type, reencoded.info.background), (2, Some([0, 0, 65535])));
+        assert!(reencoded.chunks.is_empty());
+
+        // Without bKGD the given color is used, without either there's nothing to flatten onto
+        let bytes = build_png(2, 1, 8, 6, 0, &raw, &[]);
+        let mut image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        assert!(image.flatten_alpha(None).is_err());
+        image.flatten_alpha(Some([255, 255, 255])).unwrap();
+        assert_eq!(image.rgba, vec![255, 255, 255, 255, 255, 255, 255, 255]);
+
+        // 16-bit samples are composited at full precision
+        let bytes = build_png(1, 1, 16, 4, 0, &[0, 0x12, 0x34, 0, 0], &[(BKGD, vec![0xab, 0xcd])]);
+        let mut image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        image.flatten_alpha(None).unwrap();
+        assert_eq!(image.rgba16, Some(vec![0xabcd, 0xabcd, 0xabcd, 0xffff]));
+        let reencoded = DecodedPng::from_bytes(&image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap(), None, &pb).unwrap();
+        assert_eq!((reencoded.info.color_type, reencoded.info.bit_depth, reencoded.info.background), (0, 16, Some([0xabcd; 3])));
+    }
+
+    #[test]
+    fn test_lossless_bit_depth_reduction() {
+        use crate::png::constants::SBIT;
+        use crate::png::SignificantBits;
+        let pb = ProgressBar::hidden();
+        let roundtrip = |image: &DecodedPng, interlacing: Interlacing| {
+            DecodedPng::from_bytes(&image.encode_optimized(CompressionLevel::Lossless, interlacing, None, &pb).unwrap(), None, &pb).unwrap()
+        };
+
+        // 8-bit data scaled up to 16 bits goes back to 8 bits
+        let image = DecodedPng::from_bytes(&build_png(1, 1, 16, 2, 0, &[0, 0x12, 0x12, 0x34, 0x34, 0x56, 0x56], &[]), None, &pb).unwrap();
+        let reencoded = roundtrip(&image, Interlacing::None);
+        assert_eq!((reencoded.info.bit_depth, reencoded.rgba16, reencoded.rgba), (8, None, vec![0x12, 0x34, 0x56, 255]));
+
+        // Real 16-bit data stays, unless sBIT says the low bits don't mean anything
+        let raw = [0u8, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+        let image = DecodedPng::from_bytes(&build_png(1, 1, 16, 2, 0, &raw, &[]), None, &pb).unwrap();
+        assert_eq!(roundtrip(&image, Interlacing::None).info.bit_depth, 16);
+        let image = DecodedPng::from_bytes(&build_png(1, 1, 16, 2, 0, &raw, &[(SBIT, vec![8, 7, 8])]), None, &pb).unwrap();
+        assert_eq!(image.info.significant_bits, Some(SignificantBits { rgb: [8, 7, 8], alpha: None }));
+        let reencoded = roundtrip(&image, Interlacing::None);
+        assert_eq!((reencoded.info.bit_depth, reencoded.info.significant_bits), (8, image.info.significant_bits));
+        assert_eq!(reencoded.rgba, vec![0x12, 0x56, 0x9a, 255]);
+
+        // Gray that only uses a few levels is packed below 8 bits, interlaced too
+        let image = DecodedPng::from_bytes(&build_png(4, 1, 8, 0, 0, &[0, 0, 85, 170, 255], &[]), None, &pb).unwrap();
+        let reencoded = roundtrip(&image, Interlacing::None);
+        assert_eq!((reencoded.info.bit_depth, &reencoded.rgba), (2, &image.rgba));
+        let raw: Vec<u8> = (0..3).flat_map(|y| std::iter::once(0).chain((0..5).map(move |x| if (x + y) % 2 == 0 { 255 } else { 0 }))).collect();
+        let image = DecodedPng::from_bytes(&build_png(5, 3, 8, 0, 0, &raw, &[]), None, &pb).unwrap();
+        let reencoded = roundtrip(&image, Interlacing::Adam7);
+        assert_eq!((reencoded.info.bit_depth, reencoded.info.interlace, &reencoded.rgba), (1, 1, &image.rgba));
+    }
+
+    #[test]
+    fn test_hdr_chunks_and_tone_mapping() {
+        use crate::png::constants::{CICP, CLLI, MDCV};
+        use crate::png::{Cicp, ContentLightLevel, MasteringDisplay, RenderingIntent};
+        let pb = ProgressBar::hidden();
+
+        // BT.2020 PQ, mastered on a 0.005-1000 cd/m² display, brightest pixel 1000 cd/m²
+        let display = MasteringDisplay { primaries: [(35400, 14600), (8500, 39850), (6550, 2300)], white: (15635, 16450), max_luminance: 10_000_000, min_luminance: 50 };
+        let level = ContentLightLevel { max_cll: 10_000_000, max_fall: 4_000_000 };
+        // PQ 148 is about SDR white (203 cd/m²), 192 is just past 1000 cd/m²
+        let bytes = build_png(2, 1, 8, 0, 0, &[0, 148, 192], &[
+            (CICP, vec![9, 16, 0, 1]),
+            (MDCV, display.to_bytes()),
+            (CLLI, level.to_bytes()),
+        ]);
+        let mut image = DecodedPng::from_bytes(&bytes, None, &pb).unwrap();
+        let cicp = Cicp { colour_primaries: 9, transfer_function: 16, matrix_coefficients: 0, full_range: true };
+        assert_eq!((image.info.cicp, image.info.mastering_display, image.info.content_light_level), (Some(cicp), Some(display), Some(level)));
+        assert!(image.chunks.is_empty());
+
+        let reencoded = DecodedPng::from_bytes(&image.encode_optimized(CompressionLevel::Lossless, Interlacing::None, None, &pb).unwrap(), None, &pb).unwrap();
+        assert_eq!((reencoded.info.cicp, reencoded.info.mastering_display, reencoded.info.content_light_level), (Some(cicp), Some(display), Some(level)));
+
+        // Lossy output is refused unless forced
+        let lossy = ProcessOptions { compression_level: CompressionLevel::Balanced, ..Default::default() };
+        assert!(check_lossy_hdr(&image, &lossy).is_err());
+        assert!(image.check_lossy_hdr(&CompressionLevel::Maximum).is_err() && image.check_lossy_hdr(&CompressionLevel::Lossless).is_ok());
+        assert!(check_lossy_hdr(&image, &ProcessOptions { force_lossy: true, ..lossy.clone() }).is_ok());
+
+        // SDR white lands mid-range with headroom left for highlights, the peak is white
+        image.tone_map_to_sdr().unwrap();
+        assert!((180..=200).contains(&image.rgba[0]), "{:?}", image.rgba);
+        assert_eq!(image.rgba[4..], [255, 255, 255, 255]);
+        assert_eq!((image.info.cicp, image.info.content_light_level, image.info.srgb), (None, None, Some(RenderingIntent::Perceptual)));
+        assert!(check_lossy_hdr(&image, &lossy).is_ok());
+        assert!(image.tone_map_to_sdr().is_err());
+
+        // Display P3 is converted by its cICP, which makes way for the sRGB chunk. P3 orange is more saturated
+        // than sRGB can show, gray stays put
+        let p3 = build_png(2, 1, 8, 2, 0, &[0, 230, 120, 20, 128, 128, 128], &[(CICP, vec![12, 13, 0, 1]), (CLLI, level.to_bytes())]);
+        let mut image = DecodedPng::from_bytes(&p3, None, &pb).unwrap();
+        image.normalize_to_srgb().unwrap();
+        assert!(image.rgba[0] > 230 && image.rgba[2] < 20, "{:?}", image.rgba);
+        assert_eq!(image.rgba[4..], [128, 128, 128, 255]);
+        assert_eq!((image.info.cicp, image.info.content_light_level, image.info.srgb), (None, None, Some(RenderingIntent::Perceptual)));
+
+        // A transfer function we can't convert from is refused rather than mislabeled
+        let unknown = build_png(1, 1, 8, 0, 0, &[0, 128], &[(CICP, vec![1, 2, 0, 1])]);
+        let mut image = DecodedPng::from_bytes(&unknown, None, &pb).unwrap();
+        assert!(image.normalize_to_srgb().is_err());
+    }
+
+    #[test]
+    fn test_apng_decoding_and_compositing() {
+        use crate::png::Limits;
+        use crate::png::apng::{AnimatedPng, BlendOp, DisposeOp};
+        use crate::png::constants::{ACTL, FCTL, FDAT};
+        use crate::png::error::PngError;
+        let pb = ProgressBar::hidden();
+
+        let fctl = |sequence: u32, (width, height, x, y): (u32, u32, u32, u32), dispose: u8, blend: u8| {
+            let mut data: Vec<u8> = [sequence, width, height, x, y].iter().flat_map(|v| v.to_be_bytes()).collect();
+            data.extend_from_slice(&[0, 10, 0, 100, dispose, blend]);
+            data
+        };
+        let fdat = |sequence: u32, raw: &[u8]| {
+            let mut encoder = ZlibEncoder::new(sequence.to_be_bytes().to_vec(), flate2::Compression::default());
+            encoder.write_all(raw).unwrap();
+            encoder.finish().unwrap()
+        };
+        // Frame 0 is the red default image, frame 1 half transparent blue over the bottom right pixel and then
+        // cleared, frame 2 replaces the top left pixel with green
+        let build = |last_sequence: u32| {
+            let red = [0u8, 255, 0, 0, 255, 255, 0, 0, 255];
+            let mut bytes = build_png(2, 2, 8, 6, 0, &[red, red].concat(), &[
+                (ACTL, [3u32, 0].iter().flat_map(|v| v.to_be_bytes()).collect()),
+                (FCTL, fctl(0, (2, 2, 0, 0), 0, 0)),
+            ]);
+            let iend = bytes.split_off(bytes.len() - 12);
+            write_chunk(&mut bytes, &FCTL, &fctl(1, (1, 1, 1, 1), 1, 1), None).unwrap();
+            write_chunk(&mut bytes, &FDAT, &fdat(2, &[0, 0, 0, 255, 128]), None).unwrap();
+            write_chunk(&mut bytes, &FCTL, &fctl(3, (1, 1, 0, 0), 0, 0), None).unwrap();
+            write_chunk(&mut bytes, &FDAT, &fdat(last_sequence, &[0, 0, 255, 0, 255]), None).unwrap();
+            bytes.extend_from_slice(&iend);
+            bytes
+        };
+
+        let animation = AnimatedPng::from_bytes(&build(4), None, &DecodeOptions::default(), &pb).unwrap();
+        assert!(animation.default_image_is_frame && animation.image.chunks.is_empty());
+        assert_eq!((animation.num_plays, animation.frames.len()), (0, 3));
+        let (red, clear) = ([255, 0, 0, 255], [0, 0, 0, 0]);
+        assert_eq!(animation.frames[0].rgba, [red, red, red, red].concat());
+        assert_eq!(animation.frames[1].rgba, [red, red, red, [127, 0, 128, 255]].concat());
+        assert_eq!(animation.frames[2].rgba, [[0, 255, 0, 255], red, red, clear].concat());
+        let control = animation.frames[1].control;
+        assert_eq!((control.dispose_op, control.blend_op, control.delay()), (DisposeOp::Background, BlendOp::Over, 0.1));
+
+        // A skipped sequence number means a chunk went missing
+        let error = AnimatedPng::from_bytes(&build(5), None, &DecodeOptions::default(), &pb).unwrap_err();
+        assert!(error.to_string().contains("sequence number 5, expected 4"), "{}", error);
+
+        // 3 composited 2x2 canvases take 48 bytes, more than the limit even though each frame's data fits
+        let options = DecodeOptions { limits: Limits { max_decompressed_bytes: 47, ..Default::default() }, ..Default::default() };
+        assert!(DecodedPng::from_bytes_with_options(&build(4), None, &options, &pb).is_ok());
+        assert!(matches!(AnimatedPng::from_bytes(&build(4), None, &options, &pb), Err(PngError::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn test_apng_encoding_and_frame_optimization() {
+        let pb = ProgressBar::hidden();
+        // A gray 16x16 canvas with a red 2x2 square that moves right, the last frame repeats the one before
+        let frame = |square_x: usize| {
+            let mut raw = Vec::new();
+            for y in 0..16 {
+                raw.push(0);
+                for x in 0..16 {
+                    let inside = (square_x..square_x + 2).contains(&x) && (4..6).contains(&y);
+                    raw.extend_from_slice(if inside { &[255, 0, 0, 255] } else { &[128, 128, 128, 255] });
+                }
+            }
+            DecodedPng::from_bytes(&build_png(16, 16, 8, 6, 0, &raw, &[]), None, &pb).unwrap()
+        };
+        let images = vec![frame(0), frame(5), frame(10), frame(10)];
+        let expected: Vec<Vec<u8>> = images.iter().map(|image| image.rgba.clone()).collect();
+
+        let animation = AnimatedPng::from_frames(images, &[50, 100], 2).unwrap();
+        let bytes = animation.encode_optimized(CompressionLevel::Lossless, None, &pb).unwrap();
+        let decoded = AnimatedPng::from_bytes(&bytes, None, &DecodeOptions::default(), &pb).unwrap();
+
+        // The repeated frame is folded into the delay of the one before it
+        assert_eq!((decoded.num_plays, decoded.frames.len()), (2, 3));
+        let delays: Vec<f64> = decoded.frames.iter().map(|frame| frame.control.delay()).collect();
+        assert_eq!(delays, [0.05, 0.1, 0.2]);
+        for (frame, expected) in decoded.frames.iter().zip(&expected) {
+            assert_eq!(&frame.rgba, expected);
+        }
+        // Later frames only cover the pixels that changed
+        assert_eq!((decoded.frames[0].control.width, decoded.frames[0].control.height), (16, 16));
+        for frame in &decoded.frames[1..] {
+            assert!(frame.control.width <= 7 && frame.control.height == 2, "{:?}", frame.control);
+        }
+        // Optimizing again plays back the same
+        let again = decoded.encode_optimized(CompressionLevel::Lossless, None, &pb).unwrap();
+        let replayed = AnimatedPng::from_bytes(&again, None, &DecodeOptions::default(), &pb).unwrap();
+        assert!(replayed.frames.iter().zip(&decoded.frames).all(|(a, b)| a.rgba == b.rgba && a.control.delay() == b.control.delay()));
+    }
+
+    #[test]
+    fn test_apng_frame_encryption() {
+        use crate::png::constants::{FCTL, FDAT};
+        use crate::png::error::PngError;
+        let pb = ProgressBar::hidden();
+        let key = [7u8; 32];
+        // Three 4x4 frames of a diagonal line in different colors
+        let frame = |color: [u8; 4]| {
+            let mut raw = Vec::new();
+            for y in 0..4 {
+                raw.push(0);
+                for x in 0..4 {
+                    raw.extend_from_slice(if x == y { &color } else { &[0, 0, 0, 0] });
+                }
+            }
+            DecodedPng::from_bytes(&build_png(4, 4, 8, 6, 0, &raw, &[]), None, &pb).unwrap()
+        };
+        let images = vec![frame([255, 0, 0, 255]), frame([0, 255, 0, 255]), frame([0, 0, 255, 255])];
+        let expected: Vec<Vec<u8>> = images.iter().map(|image| image.rgba.clone()).collect();
+        let animation = AnimatedPng::from_frames(images, &[100], 0).unwrap();
+        let bytes = animation.encode_optimized(CompressionLevel::Lossless, Some(&key), &pb).unwrap();
+
+        // Split the file into its chunks, after the signature
+        let mut chunks = Vec::new();
+        let mut offset = 8;
+        while offset < bytes.len() {
+            let length = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            let chunk_type: [u8; 4] = bytes[offset + 4..offset + 8].try_into().unwrap();
+            chunks.push((chunk_type, bytes[offset + 8..offset + 8 + length].to_vec()));
+            offset += 12 + length;
+        }
+        let rebuild = |chunks: &[([u8; 4], Vec<u8>)]| {
+            let mut bytes = PNG_SIG.to_vec();
+            for (chunk_type, data) in chunks {
+                write_chunk(&mut bytes, chunk_type, data, None).unwrap();
+            }
+            bytes
+        };
+        let fdat: Vec<usize> = (0..chunks.len()).filter(|&i| chunks[i].0 == FDAT).collect();
+        assert_eq!(fdat.len(), 2);
+        // Sequence numbers stay readable, the frame data doesn't inflate without the key
+        assert_eq!(chunks[fdat[0]].1[..4], 2u32.to_be_bytes());
+        assert!(crate::png::zlib::inflate(&chunks[fdat[0]].1[4..], 1 << 20).is_err());
+
+        let decrypted = AnimatedPng::from_bytes(&bytes, Some(&key), &DecodeOptions::default(), &pb).unwrap();
+        assert_eq!(decrypted.frames.len(), 3);
+        for (frame, expected) in decrypted.frames.iter().zip(&expected) {
+            assert_eq!(&frame.rgba, expected);
+        }
+        // Decrypt mode writes a plain APNG that plays the same
+        let plain = decrypted.encode_optimized(CompressionLevel::Lossless, None, &pb).unwrap();
+        let replayed = AnimatedPng::from_bytes(&plain, None, &DecodeOptions::default(), &pb).unwrap();
+        assert!(replayed.frames.iter().zip(&expected).all(|(frame, expected)| &frame.rgba == expected));
+
+        // Swapping the encrypted frame data between sequence numbers fails authentication
+        let mut swapped = chunks.clone();
+        let (first, second) = (chunks[fdat[0]].1[4..].to_vec(), chunks[fdat[1]].1[4..].to_vec());
+        swapped[fdat[0]].1.truncate(4);
+        swapped[fdat[0]].1.extend_from_slice(&second);
+        swapped[fdat[1]].1.truncate(4);
+        swapped[fdat[1]].1.extend_from_slice(&first);
+        let error = AnimatedPng::from_bytes(&rebuild(&swapped), Some(&key), &DecodeOptions::default(), &pb).unwrap_err();
+        assert!(matches!(error, PngError::Decryption), "{}", error);
+        assert!(AnimatedPng::from_bytes(&rebuild(&chunks), Some(&key), &DecodeOptions::default(), &pb).is_ok());
+
+        // fcTL is authenticated the same way, changing the last frame's x offset or delay fails too. They're 8 and 16
+        // bytes into the encrypted part, which follows the sequence number and nonce.
+        let fctl = chunks.iter().rposition(|(chunk_type, _)| *chunk_type == FCTL).unwrap();
+        assert_eq!(chunks[fctl].1[..4], 3u32.to_be_bytes());
+        for position in [24, 32] {
+            let mut tampered = chunks.clone();
+            tampered[fctl].1[position] ^= 1;
+            let error = AnimatedPng::from_bytes(&rebuild(&tampered), Some(&key), &DecodeOptions::default(), &pb).unwrap_err();
+            assert!(matches!(error, PngError::Decryption), "{}", error);
+        }
+    }
+}
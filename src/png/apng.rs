@@ -0,0 +1,586 @@
+use std::io::Write;
+use indicatif::ProgressBar;
+use crate::png::constants::*;
+use crate::png::error::{PngError, Result};
+use crate::png::interlace::image_passes;
+use crate::png::read::{decode_pixels, image_data_size, DecodedPixels};
+use crate::png::types::*;
+use crate::png::write::{compress_image, optimize_pixels, pack_image, pixel_format, write_ancillary_chunk, write_chunk};
+use crate::png::zlib::inflate;
+
+// What happens to the frame's region before the next frame is drawn https://www.w3.org/TR/png-3/#fcTL-chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisposeOp {
+    #[default]
+    None = 0,
+    // Cleared to transparent black
+    Background = 1,
+    // Restored to what it was before the frame
+    Previous = 2,
+}
+
+// How the frame is drawn onto the canvas
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlendOp {
+    // Replaces the region, alpha included
+    #[default]
+    Source = 0,
+    // Alpha composited over the region
+    Over = 1,
+}
+
+// fcTL without its sequence number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameControl {
+    pub width: u32,
+    pub height: u32,
+    pub x_offset: u32,
+    pub y_offset: u32,
+    pub delay_num: u16,
+    pub delay_den: u16,
+    pub dispose_op: DisposeOp,
+    pub blend_op: BlendOp,
+}
+
+impl FrameControl {
+    pub fn parse(data: &[u8]) -> Result<(u32, FrameControl)> {
+        if data.len() != 26 {
+            return Err(PngError::Malformed(format!("fcTL length is {}, expected 26", data.len())));
+        }
+        let u32_at = |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+        let dispose_op = match data[24] {
+            0 => DisposeOp::None,
+            1 => DisposeOp::Background,
+            2 => DisposeOp::Previous,
+            op => return Err(PngError::Malformed(format!("fcTL dispose op {} is invalid", op))),
+        };
+        let blend_op = match data[25] {
+            0 => BlendOp::Source,
+            1 => BlendOp::Over,
+            op => return Err(PngError::Malformed(format!("fcTL blend op {} is invalid", op))),
+        };
+        let control = FrameControl {
+            width: u32_at(4),
+            height: u32_at(8),
+            x_offset: u32_at(12),
+            y_offset: u32_at(16),
+            delay_num: u16_at(20),
+            delay_den: u16_at(22),
+            dispose_op,
+            blend_op,
+        };
+        Ok((u32_at(0), control))
+    }
+
+    pub fn to_bytes(self, sequence: u32) -> Vec<u8> {
+        let mut data = Vec::with_capacity(26);
+        for value in [sequence, self.width, self.height, self.x_offset, self.y_offset] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(&self.delay_num.to_be_bytes());
+        data.extend_from_slice(&self.delay_den.to_be_bytes());
+        data.push(self.dispose_op as u8);
+        data.push(self.blend_op as u8);
+        data
+    }
+
+    // Seconds the frame stays on screen, a denominator of 0 means hundredths
+    pub fn delay(&self) -> f64 {
+        let den = if self.delay_den == 0 { 100 } else { self.delay_den };
+        self.delay_num as f64 / den as f64
+    }
+
+    // Delay of this frame followed by `next`, None if it doesn't fit in the 16-bit fraction
+    fn combined_delay(&self, next: &FrameControl) -> Option<(u16, u16)> {
+        if self.delay_den == next.delay_den {
+            return Some((self.delay_num.checked_add(next.delay_num)?, self.delay_den));
+        }
+        let milliseconds = ((self.delay() + next.delay()) * 1000.0).round();
+        (milliseconds <= u16::MAX as f64).then_some((milliseconds as u16, 1000))
+    }
+}
+
+// One frame of the animation as a viewer shows it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub control: FrameControl,
+    // The whole canvas after the frame was drawn onto it
+    pub rgba: Vec<u8>,
+    pub rgba16: Option<Vec<u16>>,
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct AnimatedPng {
+    // The IDAT image decoders without APNG support show, along with the file's metadata
+    pub image: DecodedPng,
+    // 0 loops forever
+    pub num_plays: u32,
+    // Whether the IDAT image is also the first frame
+    pub default_image_is_frame: bool,
+    pub frames: Vec<Frame>,
+}
+
+pub fn is_animated(image: &DecodedPng) -> bool {
+    image.chunks.iter().any(|chunk| chunk.chunk_type == ACTL)
+}
+
+impl AnimatedPng {
+    // Every image becomes a full canvas frame, the first one also provides the metadata and the default image.
+    // Delays are in milliseconds, the last one is used for any frames past the end of the list.
+    pub fn from_frames(images: Vec<DecodedPng>, delays: &[u16], num_plays: u32) -> Result<AnimatedPng> {
+        let first = images.first().ok_or_else(|| PngError::Unsupported("an animation without frames".to_string()))?;
+        let (width, height) = (first.info.width, first.info.height);
+        if let Some(other) = images.iter().find(|image| (image.info.width, image.info.height) != (width, height)) {
+            return Err(PngError::Unsupported(format!("frames of different sizes, {}x{} and {}x{}", width, height, other.info.width, other.info.height)));
+        }
+        let high_depth = images.iter().any(|image| image.rgba16.is_some());
+
+        let frames = images.iter().enumerate().map(|(i, image)| {
+            let delay = delays.get(i).or(delays.last()).copied().unwrap_or(100);
+            // 8-bit frames are widened exactly when others have 16-bit samples
+            let rgba16 = high_depth.then(|| image.rgba16.clone().unwrap_or_else(|| image.rgba.iter().map(|&sample| sample as u16 * 257).collect()));
+            Frame {
+                control: FrameControl { width, height, delay_num: delay, delay_den: 1000, ..Default::default() },
+                rgba: image.rgba.clone(),
+                rgba16,
+            }
+        }).collect();
+
+        let image = images.into_iter().next().unwrap_or_default();
+        Ok(AnimatedPng { image, num_plays, default_image_is_frame: true, frames })
+    }
+
+    #[allow(dead_code)]
+    pub fn from_bytes(bytes: &[u8], decryption_key: Option<&[u8; 32]>, options: &DecodeOptions, pb: &ProgressBar) -> Result<AnimatedPng> {
+        let image = DecodedPng::from_bytes_with_options(bytes, decryption_key, options, pb)?;
+        AnimatedPng::from_decoded(image, &options.limits)
+    }
+
+    // Builds the animation out of the acTL, fcTL and fdAT chunks the decoder kept, which are taken out of `image.chunks`
+    pub fn from_decoded(mut image: DecodedPng, limits: &Limits) -> Result<AnimatedPng> {
+        let (animation, chunks): (Vec<_>, Vec<_>) = std::mem::take(&mut image.chunks).into_iter()
+            .partition(|chunk| [ACTL, FCTL, FDAT].contains(&chunk.chunk_type));
+        image.chunks = chunks;
+
+        let mut animation_control = None;
+        // fcTL and fdAT share one sequence that starts at 0 and has no gaps or repeats
+        let mut next_sequence = 0u32;
+        let mut check_sequence = |sequence: u32| {
+            if sequence != next_sequence {
+                return Err(PngError::Malformed(format!("APNG sequence number {}, expected {}", sequence, next_sequence)));
+            }
+            next_sequence += 1;
+            Ok(())
+        };
+        let mut frame_data: Vec<(FrameControl, ChunkPosition, Vec<u8>)> = Vec::new();
+
+        for chunk in animation {
+            match chunk.chunk_type {
+                ACTL => {
+                    let [a, b, c, d, e, f, g, h] = chunk.data[..] else {
+                        return Err(PngError::Malformed(format!("acTL length is {}, expected 8", chunk.data.len())));
+                    };
+                    if animation_control.is_some() {
+                        return Err(PngError::Malformed("Multiple acTL chunks".to_string()));
+                    }
+                    animation_control = Some((u32::from_be_bytes([a, b, c, d]), u32::from_be_bytes([e, f, g, h])));
+                },
+                FCTL => {
+                    let (sequence, control) = FrameControl::parse(&chunk.data)?;
+                    check_sequence(sequence)?;
+                    frame_data.push((control, chunk.position, Vec::new()));
+                },
+                _ => {
+                    let Some((sequence, data)) = chunk.data.split_first_chunk::<4>() else {
+                        return Err(PngError::Malformed("fdAT is missing its sequence number".to_string()));
+                    };
+                    check_sequence(u32::from_be_bytes(*sequence))?;
+                    // The default image's frame gets its data from IDAT
+                    match frame_data.last_mut() {
+                        Some((_, ChunkPosition::AfterIdat, frame)) => frame.extend_from_slice(data),
+                        _ => return Err(PngError::Malformed("fdAT must follow an fcTL that comes after IDAT".to_string())),
+                    }
+                },
+            }
+        }
+
+        let (num_frames, num_plays) = animation_control.ok_or_else(|| PngError::Malformed("Image has no acTL chunk, it isn't animated".to_string()))?;
+        if num_frames == 0 || num_frames as usize != frame_data.len() {
+            return Err(PngError::Malformed(format!("acTL declares {} frames, found {}", num_frames, frame_data.len())));
+        }
+
+        let info = &image.info;
+        // Every frame is kept as a full canvas, 16-bit ones twice (8 and 16-bit samples), so a few tiny fdATs can
+        // claim gigabytes. Check the total before decoding anything
+        let canvas_bytes = info.width as u64 * info.height as u64 * 4 * if info.bit_depth == 16 { 3 } else { 1 };
+        match canvas_bytes.checked_mul(num_frames as u64) {
+            Some(total) if total <= limits.max_decompressed_bytes => {},
+            _ => return Err(PngError::LimitExceeded(format!("{} frames of {}x{} need more than {} bytes", num_frames, info.width, info.height, limits.max_decompressed_bytes))),
+        }
+        let default_image_is_frame = frame_data[0].1 != ChunkPosition::AfterIdat;
+        let mut frames = Vec::with_capacity(frame_data.len());
+        for (i, (control, _, data)) in frame_data.into_iter().enumerate() {
+            let fits = control.width > 0 && control.height > 0
+                && control.x_offset.checked_add(control.width).is_some_and(|right| right <= info.width)
+                && control.y_offset.checked_add(control.height).is_some_and(|bottom| bottom <= info.height);
+            if !fits {
+                return Err(PngError::Malformed(format!("Frame {} doesn't fit on the {}x{} canvas", i, info.width, info.height)));
+            }
+
+            if i == 0 && default_image_is_frame {
+                if (control.width, control.height, control.x_offset, control.y_offset) != (info.width, info.height, 0, 0) {
+                    return Err(PngError::Malformed("The default image's fcTL must cover the whole canvas".to_string()));
+                }
+                frames.push((control, image.rgba.clone(), image.rgba16.clone()));
+                continue;
+            }
+
+            let frame_info = PngInfo { width: control.width, height: control.height, ..info.clone() };
+            let expected = image_data_size(&frame_info, &image_passes(info.interlace, control.width as usize, control.height as usize), limits)?;
+            let (raw, adler32_mismatch) = inflate(&data, expected as u64)?;
+            if let Some(mismatch) = adler32_mismatch {
+                return Err(PngError::BadAdler32(mismatch));
+            }
+            if raw.len() != expected {
+                return Err(PngError::Malformed(format!("Frame {} image data is {} bytes, expected {}", i, raw.len(), expected)));
+            }
+            let DecodedPixels { rgba, rgba16, .. } = decode_pixels(&frame_info, &raw)?;
+            frames.push((control, rgba, rgba16));
+        }
+
+        let (width, height) = (info.width as usize, info.height as usize);
+        let frames = if info.bit_depth == 16 {
+            let frames16: Vec<_> = frames.into_iter().map(|(control, _, rgba16)| (control, rgba16.unwrap_or_default())).collect();
+            play(width, height, &frames16).into_iter().zip(&frames16).map(|(canvas, (control, _))| Frame {
+                control: *control,
+                rgba: canvas.iter().map(|&sample| (sample >> 8) as u8).collect(),
+                rgba16: Some(canvas),
+            }).collect()
+        } else {
+            let frames8: Vec<_> = frames.into_iter().map(|(control, rgba, _)| (control, rgba)).collect();
+            play(width, height, &frames8).into_iter().zip(&frames8).map(|(canvas, (control, _))| Frame {
+                control: *control,
+                rgba: canvas,
+                rgba16: None,
+            }).collect()
+        };
+
+        Ok(AnimatedPng { image, num_plays, default_image_is_frame, frames })
+    }
+}
+
+impl AnimatedPng {
+    // Encodes the frames with the smallest regions that reproduce them: identical consecutive frames are merged,
+    // and each frame is cropped to what changed after trying every dispose op of the frame before it and both
+    // blend ops. Frames aren't interlaced, Adam7 only makes the many small frames bigger. Lossy levels quantize
+    // HDR frames too, see `check_lossy_hdr`.
+    pub fn encode_optimized(&self, compression_level: CompressionLevel, encryption_key: Option<&[u8; 32]>, pb: &ProgressBar) -> Result<Vec<u8>> {
+        let info = &self.image.info;
+        let width = info.width as usize;
+
+        pb.set_message("Optimizing frames...");
+        let frames = merge_identical(&self.frames);
+        let default_rgba = optimize_pixels(&self.image.rgba, &compression_level);
+        let rgba: Vec<Vec<u8>> = frames.iter().map(|frame| optimize_pixels(&frame.rgba, &compression_level)).collect();
+        let rgba16: Vec<&[u16]> = frames.iter().filter_map(|frame| frame.rgba16.as_deref()).chain(self.image.rgba16.as_deref()).collect();
+        let all_rgba: Vec<&[u8]> = rgba.iter().map(Vec::as_slice).chain([default_rgba.as_slice()]).collect();
+        let (bit_depth, color_type) = pixel_format(&all_rgba, &rgba16, &compression_level, info.significant_bits);
+        pb.inc(1);
+
+        pb.set_message("Compressing frames...");
+        let controls: Vec<FrameControl> = frames.iter().map(|frame| frame.control).collect();
+        let planned = if bit_depth == 16 {
+            let canvases: Vec<&[u16]> = frames.iter().map(|frame| frame.rgba16.as_deref().unwrap_or_default()).collect();
+            plan_frames(&canvases, &controls, width, color_type, bit_depth, self.default_image_is_frame)?
+        } else {
+            let canvases: Vec<&[u8]> = rgba.iter().map(Vec::as_slice).collect();
+            plan_frames(&canvases, &controls, width, color_type, bit_depth, self.default_image_is_frame)?
+        };
+        let compress = |width: u32, height: u32, image_data: &[u8]| {
+            compress_image(image_data, width as usize, height as usize, color_type, bit_depth, Interlacing::None, &compression_level)
+                .map(|(_, compressed)| compressed)
+        };
+        // A default image that isn't part of the animation is written on its own
+        let default_image = match self.default_image_is_frame {
+            true => None,
+            false => Some(compress(info.width, info.height, &pack_image(&default_rgba, self.image.rgba16.as_deref(), color_type, bit_depth))?),
+        };
+        let compressed = planned.iter()
+            .map(|(control, image_data)| compress(control.width, control.height, image_data).map(|data| (*control, data)))
+            .collect::<Result<Vec<_>>>()?;
+        pb.inc(2);
+
+        pb.set_message("Writing image...");
+        let format = PngInfo { width: info.width, height: info.height, bit_depth, color_type, ..Default::default() };
+        let mut output_bytes = Vec::new();
+        self.image.write_header(&mut output_bytes, &compression_level, &format, encryption_key)?;
+
+        // https://www.w3.org/TR/png-3/#acTL-chunk
+        let mut actl = (compressed.len() as u32).to_be_bytes().to_vec();
+        actl.extend_from_slice(&self.num_plays.to_be_bytes());
+        write_ancillary_chunk(&mut output_bytes, &ACTL, &actl, encryption_key)?;
+
+        if let Some(data) = &default_image {
+            write_chunk(&mut output_bytes, &IDAT, data, encryption_key)?;
+        }
+        let mut sequence = 0u32;
+        for (i, (control, data)) in compressed.iter().enumerate() {
+            write_ancillary_chunk(&mut output_bytes, &FCTL, &control.to_bytes(sequence), encryption_key)?;
+            sequence += 1;
+            if i == 0 && default_image.is_none() {
+                write_chunk(&mut output_bytes, &IDAT, data, encryption_key)?;
+                continue;
+            }
+            let fdat = [sequence.to_be_bytes().as_slice(), data].concat();
+            write_ancillary_chunk(&mut output_bytes, &FDAT, &fdat, encryption_key)?;
+            sequence += 1;
+        }
+
+        self.image.write_trailer(&mut output_bytes, &compression_level, &format, encryption_key)?;
+        pb.inc(1);
+
+        Ok(output_bytes)
+    }
+
+    pub async fn save_optimized_async(&self, path: &str, compression_level: CompressionLevel, encryption_key: Option<[u8; 32]>, pb: &ProgressBar) -> Result<()> {
+        let this = self.clone();
+        let pb_clone = pb.clone();
+        let encoded_bytes = smol::unblock(move || {
+            this.encode_optimized(compression_level, encryption_key.as_ref(), &pb_clone)
+        }).await?;
+
+        smol::fs::write(path, &encoded_bytes)
+            .await
+            .map_err(|e| PngError::file(e, "write", path))?;
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn save_optimized(&self, path: &str, compression_level: CompressionLevel, encryption_key: Option<&[u8; 32]>, pb: &ProgressBar) -> Result<()> {
+        let encoded_bytes = self.encode_optimized(compression_level, encryption_key, pb)?;
+        let mut file = std::fs::File::create(path).map_err(|e| PngError::file(e, "create", path))?;
+        file.write_all(&encoded_bytes)?;
+        Ok(())
+    }
+}
+
+// Folds each frame that shows the same canvas as the one before it into that frame's delay
+fn merge_identical(frames: &[Frame]) -> Vec<Frame> {
+    let mut merged: Vec<Frame> = Vec::with_capacity(frames.len());
+    for frame in frames {
+        if let Some(last) = merged.last_mut()
+            && last.rgba == frame.rgba && last.rgba16 == frame.rgba16
+            && let Some((delay_num, delay_den)) = last.control.combined_delay(&frame.control) {
+            last.control.delay_num = delay_num;
+            last.control.delay_den = delay_den;
+            continue;
+        }
+        merged.push(frame.clone());
+    }
+    merged
+}
+
+// Picks the region, dispose and blend ops of every frame so that playing them reproduces `canvases`, returning
+// each frame's control and packed image data. Candidates are ranked by their size with fast compression.
+fn plan_frames<T: Sample>(canvases: &[&[T]], controls: &[FrameControl], width: usize, color_type: u8, bit_depth: u8, default_image_is_frame: bool) -> Result<Vec<(FrameControl, Vec<u8>)>> {
+    let height = canvases[0].len() / 4 / width;
+    // Transparent pixels for the unchanged parts of an Over frame need an alpha channel
+    let can_blend = color_type & 4 != 0;
+    let rank = |rect: &Rect, pixels: &[T]| -> Result<usize> {
+        let image_data = T::pack(pixels, color_type, bit_depth);
+        let (_, compressed) = compress_image(&image_data, rect.width, rect.height, color_type, bit_depth, Interlacing::None, &CompressionLevel::Lossless)?;
+        Ok(compressed.len())
+    };
+
+    let blank = vec![T::default(); canvases[0].len()];
+    // The canvas the last frame was drawn onto, and the region it covered
+    let mut before = blank.clone();
+    let mut last_rect = Rect { x: 0, y: 0, width, height };
+    let mut planned: Vec<(FrameControl, Vec<T>)> = Vec::with_capacity(canvases.len());
+
+    for (i, (target, control)) in canvases.iter().zip(controls).enumerate() {
+        // The default image has to cover the whole canvas
+        if i == 0 && default_image_is_frame {
+            planned.push((FrameControl { dispose_op: DisposeOp::None, blend_op: BlendOp::Source, ..*control }, target.to_vec()));
+            continue;
+        }
+
+        let mut bases: Vec<(DisposeOp, Vec<T>)> = match i {
+            0 => vec![(DisposeOp::None, blank.clone())],
+            _ => {
+                let shown = canvases[i - 1];
+                let mut cleared = shown.to_vec();
+                for y in last_rect.y..last_rect.y + last_rect.height {
+                    cleared[(y * width + last_rect.x) * 4..(y * width + last_rect.x + last_rect.width) * 4].fill(T::default());
+                }
+                vec![(DisposeOp::None, shown.to_vec()), (DisposeOp::Background, cleared), (DisposeOp::Previous, before.clone())]
+            },
+        };
+
+        let mut best: Option<(usize, usize, BlendOp, Rect, Vec<T>)> = None;
+        for (base_index, (_, base)) in bases.iter().enumerate() {
+            // A frame that changes nothing still needs one pixel
+            let rect = changed_rect(base, target, width, height).unwrap_or(Rect { x: 0, y: 0, width: 1, height: 1 });
+            let source = rect.pixels(target, width);
+            let old = rect.pixels(base, width);
+            let mut candidates = Vec::with_capacity(2);
+            // Over can only keep a pixel or draw an opaque one, unchanged pixels are written transparent
+            if can_blend && old.chunks_exact(4).zip(source.chunks_exact(4)).all(|(old, new)| old == new || new[3].get() == T::MAX) {
+                let over = old.chunks_exact(4).zip(source.chunks_exact(4))
+                    .flat_map(|(old, new)| if old == new { [T::default(); 4] } else { [new[0], new[1], new[2], new[3]] })
+                    .collect();
+                candidates.push((BlendOp::Over, over));
+            }
+            candidates.push((BlendOp::Source, source));
+
+            for (blend_op, pixels) in candidates {
+                let size = rank(&rect, &pixels)?;
+                if best.as_ref().is_none_or(|best| size < best.0) {
+                    best = Some((size, base_index, blend_op, rect, pixels));
+                }
+            }
+        }
+        let (_, base_index, blend_op, rect, pixels) = best.expect("every frame has a Source candidate");
+
+        let (dispose_op, base) = bases.swap_remove(base_index);
+        if let Some((last, _)) = planned.last_mut() {
+            last.dispose_op = dispose_op;
+        }
+        before = base;
+        last_rect = rect;
+        let control = FrameControl {
+            width: rect.width as u32,
+            height: rect.height as u32,
+            x_offset: rect.x as u32,
+            y_offset: rect.y as u32,
+            dispose_op: DisposeOp::None,
+            blend_op,
+            ..*control
+        };
+        planned.push((control, pixels));
+    }
+
+    Ok(planned.into_iter().map(|(control, pixels)| (control, T::pack(&pixels, color_type, bit_depth))).collect())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Rect {
+    fn pixels<T: Copy>(&self, canvas: &[T], canvas_width: usize) -> Vec<T> {
+        let mut pixels = Vec::with_capacity(self.width * self.height * 4);
+        for y in self.y..self.y + self.height {
+            pixels.extend_from_slice(&canvas[(y * canvas_width + self.x) * 4..(y * canvas_width + self.x + self.width) * 4]);
+        }
+        pixels
+    }
+}
+
+// Bounding rectangle of the pixels that differ, None if the canvases are the same
+fn changed_rect<T: PartialEq>(old: &[T], new: &[T], width: usize, height: usize) -> Option<Rect> {
+    let (mut left, mut top, mut right, mut bottom) = (width, height, 0, 0);
+    for y in 0..height {
+        for x in 0..width {
+            let i = (y * width + x) * 4;
+            if old[i..i + 4] != new[i..i + 4] {
+                left = left.min(x);
+                top = top.min(y);
+                right = right.max(x + 1);
+                bottom = bottom.max(y + 1);
+            }
+        }
+    }
+    (right > left).then_some(Rect { x: left, y: top, width: right - left, height: bottom - top })
+}
+
+// A sample type frames can be composited and encoded in
+trait Sample: Copy + Default + PartialEq {
+    const MAX: u64;
+    fn get(self) -> u64;
+    fn from(value: u64) -> Self;
+    fn pack(pixels: &[Self], color_type: u8, bit_depth: u8) -> Vec<u8>;
+}
+
+impl Sample for u8 {
+    const MAX: u64 = 255;
+    fn get(self) -> u64 { self as u64 }
+    fn from(value: u64) -> Self { value as u8 }
+    fn pack(pixels: &[u8], color_type: u8, bit_depth: u8) -> Vec<u8> { pack_image(pixels, None, color_type, bit_depth) }
+}
+
+impl Sample for u16 {
+    const MAX: u64 = 65535;
+    fn get(self) -> u64 { self as u64 }
+    fn from(value: u64) -> Self { value as u16 }
+    fn pack(pixels: &[u16], color_type: u8, bit_depth: u8) -> Vec<u8> { pack_image(&[], Some(pixels), color_type, bit_depth) }
+}
+
+// Draws each frame onto a canvas that starts out transparent black, returning the canvas as shown for each frame
+// https://www.w3.org/TR/png-3/#apng-frame-based-animation
+fn play<T: Sample>(width: usize, height: usize, frames: &[(FrameControl, Vec<T>)]) -> Vec<Vec<T>> {
+    let mut canvas = vec![T::default(); width * height * 4];
+    let mut shown = Vec::with_capacity(frames.len());
+
+    for (control, pixels) in frames {
+        // On the first frame "previous" is the empty canvas, the same as clearing it
+        let previous = (control.dispose_op == DisposeOp::Previous).then(|| canvas.clone());
+        let region = |y: usize| {
+            let start = ((control.y_offset as usize + y) * width + control.x_offset as usize) * 4;
+            start..start + control.width as usize * 4
+        };
+
+        for y in 0..control.height as usize {
+            let row = &pixels[y * control.width as usize * 4..(y + 1) * control.width as usize * 4];
+            let target = &mut canvas[region(y)];
+            match control.blend_op {
+                BlendOp::Source => target.copy_from_slice(row),
+                BlendOp::Over => {
+                    for (destination, source) in target.chunks_exact_mut(4).zip(row.chunks_exact(4)) {
+                        blend_over(destination, source);
+                    }
+                },
+            }
+        }
+        shown.push(canvas.clone());
+
+        match (control.dispose_op, previous) {
+            (DisposeOp::Previous, Some(previous)) => canvas = previous,
+            (DisposeOp::Background, _) => {
+                for y in 0..control.height as usize {
+                    canvas[region(y)].fill(T::default());
+                }
+            },
+            _ => {},
+        }
+    }
+    shown
+}
+
+// Non-premultiplied source over destination
+fn blend_over<T: Sample>(destination: &mut [T], source: &[T]) {
+    let max = T::MAX;
+    let source_alpha = source[3].get();
+    if source_alpha == max {
+        destination.copy_from_slice(source);
+        return;
+    }
+    if source_alpha == 0 {
+        return;
+    }
+
+    let destination_alpha = destination[3].get() * (max - source_alpha);
+    // Both scaled by max
+    let alpha = source_alpha * max + destination_alpha;
+    for c in 0..3 {
+        let value = source[c].get() * source_alpha * max + destination[c].get() * destination_alpha;
+        destination[c] = T::from((value + alpha / 2) / alpha);
+    }
+    destination[3] = T::from((alpha + max / 2) / max);
+}
@@ -0,0 +1,94 @@
+use crate::png::error::{PngError, Result};
+use crate::png::types::*;
+
+// https://www.w3.org/TR/png-3/#11bKGD
+// The color is stored in the image's own sample format, we keep it as 16-bit RGB so it survives a change of color type
+pub fn parse_background(data: &[u8], info: &PngInfo, palette: Option<&[[u8; 3]]>) -> Result<[u16; 3]> {
+    let sample = |i: usize| -> Result<u16> {
+        let value = u16::from_be_bytes([data[i], data[i + 1]]);
+        if info.bit_depth < 16 && value >= 1 << info.bit_depth {
+            return Err(PngError::Malformed(format!("bKGD sample {} is out of range for bit depth {}", value, info.bit_depth)));
+        }
+        Ok(scale_to_u16(value, info.bit_depth))
+    };
+
+    match (info.image_type, data.len()) {
+        (ImageType::Grayscale | ImageType::GrayscaleAlpha, 2) => {
+            let gray = sample(0)?;
+            Ok([gray; 3])
+        },
+        (ImageType::Truecolor | ImageType::TruecolorAlpha, 6) => Ok([sample(0)?, sample(2)?, sample(4)?]),
+        (ImageType::IndexedColor, 1) => {
+            let color = palette.and_then(|palette| palette.get(data[0] as usize))
+                .ok_or_else(|| PngError::Malformed(format!("bKGD palette index {} is out of range", data[0])))?;
+            Ok(color.map(|c| c as u16 * 257))
+        },
+        _ => Err(PngError::Malformed(format!("bKGD length {} is invalid for color type {}", data.len(), info.color_type))),
+    }
+}
+
+// bKGD chunk data for an image written with this color type and bit depth, None if the color can't be expressed in it
+pub fn background_chunk(background: [u16; 3], color_type: u8, bit_depth: u8, palette: Option<&[[u8; 3]]>) -> Option<Vec<u8>> {
+    let [red, green, blue] = background.map(|value| scale_from_u16(value, bit_depth).to_be_bytes());
+    match color_type {
+        0 | 4 if background[0] == background[1] && background[1] == background[2] => Some(red.to_vec()),
+        2 | 6 => Some([red, green, blue].concat()),
+        3 => {
+            let color = background.map(|value| (value >> 8) as u8);
+            palette?.iter().position(|&entry| entry == color).map(|index| vec![index as u8])
+        },
+        _ => None,
+    }
+}
+
+impl DecodedPng {
+    // Composites every pixel onto the bKGD color, or `fallback` when the file has none, leaving the image opaque
+    pub fn flatten_alpha(&mut self, fallback: Option<[u8; 3]>) -> Result<()> {
+        let background = self.info.background
+            .or(fallback.map(|color| color.map(|c| c as u16 * 257)))
+            .ok_or_else(|| PngError::Unsupported("flattening alpha without a bKGD chunk or background color".to_string()))?;
+        self.pixels_modified = true;
+
+        if let Some(rgba16) = self.rgba16.as_mut() {
+            for pixel in rgba16.chunks_exact_mut(4) {
+                let alpha = pixel[3] as u32;
+                for c in 0..3 {
+                    pixel[c] = ((pixel[c] as u32 * alpha + background[c] as u32 * (65535 - alpha) + 32767) / 65535) as u16;
+                }
+                pixel[3] = u16::MAX;
+            }
+            for (sample, sample16) in self.rgba.iter_mut().zip(rgba16.iter()) {
+                *sample = (sample16 >> 8) as u8;
+            }
+            return Ok(());
+        }
+
+        let background = background.map(|value| (value >> 8) as u32);
+        for pixel in self.rgba.chunks_exact_mut(4) {
+            let alpha = pixel[3] as u32;
+            for c in 0..3 {
+                pixel[c] = ((pixel[c] as u32 * alpha + background[c] * (255 - alpha) + 127) / 255) as u8;
+            }
+            pixel[3] = 255;
+        }
+        Ok(())
+    }
+}
+
+// Sub-byte samples scale exactly, 65535 is a multiple of 3, 15 and 255
+fn scale_to_u16(sample: u16, bit_depth: u8) -> u16 {
+    match bit_depth {
+        16 => sample,
+        _ => sample * (65535 / ((1 << bit_depth) - 1)),
+    }
+}
+
+fn scale_from_u16(sample: u16, bit_depth: u8) -> u16 {
+    match bit_depth {
+        16 => sample,
+        _ => {
+            let step = 65535 / ((1u32 << bit_depth) - 1);
+            ((sample as u32 + step / 2) / step) as u16
+        },
+    }
+}
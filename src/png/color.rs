@@ -0,0 +1,236 @@
+use crate::png::constants::*;
+use crate::png::error::{PngError, Result};
+use crate::png::icc::D50;
+use crate::png::types::*;
+
+pub type Matrix = [[f64; 3]; 3];
+
+// sRGB primaries and D65 white point, scaled like cHRM https://www.w3.org/TR/png-3/#11sRGB
+pub const SRGB_CHROMATICITIES: Chromaticities = Chromaticities {
+    white: (31270, 32900),
+    red: (64000, 33000),
+    green: (30000, 60000),
+    blue: (15000, 6000),
+};
+
+// Cone response matrix used for chromatic adaptation between white points
+const BRADFORD: Matrix = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+// https://www.w3.org/TR/png-3/#11gAMA
+pub fn parse_gamma(data: &[u8]) -> Result<u32> {
+    let [a, b, c, d] = data else {
+        return Err(PngError::Malformed(format!("gAMA length is {}, expected 4", data.len())));
+    };
+    match u32::from_be_bytes([*a, *b, *c, *d]) {
+        0 => Err(PngError::Malformed("gAMA of 0".to_string())),
+        gamma => Ok(gamma),
+    }
+}
+
+// https://www.w3.org/TR/png-3/#11cHRM
+pub fn parse_chromaticities(data: &[u8]) -> Result<Chromaticities> {
+    if data.len() != 32 {
+        return Err(PngError::Malformed(format!("cHRM length is {}, expected 32", data.len())));
+    }
+    let value = |i: usize| u32::from_be_bytes([data[i * 4], data[i * 4 + 1], data[i * 4 + 2], data[i * 4 + 3]]);
+    let chromaticities = Chromaticities {
+        white: (value(0), value(1)),
+        red: (value(2), value(3)),
+        green: (value(4), value(5)),
+        blue: (value(6), value(7)),
+    };
+    // A y of 0 would put the color at infinite luminance
+    if [chromaticities.white, chromaticities.red, chromaticities.green, chromaticities.blue].iter().any(|&(_, y)| y == 0) {
+        return Err(PngError::Malformed("cHRM has a y coordinate of 0".to_string()));
+    }
+    Ok(chromaticities)
+}
+
+// https://www.w3.org/TR/png-3/#11sRGB
+pub fn parse_srgb(data: &[u8]) -> Result<RenderingIntent> {
+    match data {
+        [0] => Ok(RenderingIntent::Perceptual),
+        [1] => Ok(RenderingIntent::RelativeColorimetric),
+        [2] => Ok(RenderingIntent::Saturation),
+        [3] => Ok(RenderingIntent::AbsoluteColorimetric),
+        _ => Err(PngError::Malformed(format!("sRGB chunk {:?} is invalid", data))),
+    }
+}
+
+// Chunk data for the color space fields of `info`, in the order they are written
+pub fn color_chunks(info: &PngInfo) -> Result<Vec<([u8; 4], Vec<u8>)>> {
+    let mut chunks = Vec::new();
+    if let Some(chromaticities) = info.chromaticities {
+        let Chromaticities { white, red, green, blue } = chromaticities;
+        let data = [white, red, green, blue].iter().flat_map(|&(x, y)| [x, y]).flat_map(u32::to_be_bytes).collect();
+        chunks.push((CHRM, data));
+    }
+    if let Some(gamma) = info.gamma {
+        chunks.push((GAMA, gamma.to_be_bytes().to_vec()));
+    }
+    if let Some(profile) = &info.icc_profile {
+        chunks.push((ICCP, profile.to_chunk_data()?));
+    }
+    if let Some(intent) = info.srgb {
+        chunks.push((SRGB, vec![intent as u8]));
+    }
+    if let Some(cicp) = info.cicp {
+        chunks.push((CICP, cicp.to_bytes()));
+    }
+    if let Some(display) = info.mastering_display {
+        chunks.push((MDCV, display.to_bytes()));
+    }
+    if let Some(level) = info.content_light_level {
+        chunks.push((CLLI, level.to_bytes()));
+    }
+    Ok(chunks)
+}
+
+impl DecodedPng {
+    // Converts the pixels from the color space cICP, iCCP, or gAMA and cHRM, describe into sRGB, then replaces those
+    // chunks with a single sRGB chunk. Images that are already sRGB, or say nothing about color, keep their pixels.
+    pub fn normalize_to_srgb(&mut self) -> Result<()> {
+        // cICP overrides everything else, and HDR needs tone mapping to fit in sRGB
+        if self.info.cicp.is_some_and(|cicp| cicp.is_hdr()) {
+            return self.tone_map_to_sdr();
+        }
+        // Then iCCP overrides sRGB, which overrides gAMA and cHRM https://www.w3.org/TR/png-3/#12Colour-space-information
+        if self.info.cicp.is_some() {
+            self.convert_cicp_to_srgb()?;
+        }
+        else if let Some(profile) = &self.info.icc_profile {
+            let (curves, to_xyz) = profile.matrix_trc()?;
+            // Profile colorants are relative to D50, sRGB's white is D65
+            let matrix = to_xyz.map(|to_xyz| -> Result<Matrix> {
+                let adapt = bradford(D50, xy_to_xyz(SRGB_CHROMATICITIES.white))?;
+                Ok(multiply(&invert(&rgb_to_xyz(&SRGB_CHROMATICITIES)?)?, &multiply(&adapt, &to_xyz)))
+            }).transpose()?;
+            convert_to_srgb(self, |c, value| curves[c].eval(value), matrix);
+            self.pixels_modified = true;
+        }
+        else if self.info.srgb.is_none() && (self.info.gamma.is_some() || self.info.chromaticities.is_some()) {
+            let matrix = self.info.chromaticities.map(|chromaticities| conversion_to_srgb(&chromaticities)).transpose()?;
+            // Without gAMA the samples are taken to already use the sRGB curve
+            let exponent = self.info.gamma.map(|gamma| 100000.0 / gamma as f64);
+            convert_to_srgb(self, |_, value| match exponent {
+                Some(exponent) => value.powf(exponent),
+                None => srgb_to_linear(value),
+            }, matrix);
+            self.pixels_modified = true;
+        }
+
+        self.info.cicp = None;
+        self.info.mastering_display = None;
+        self.info.content_light_level = None;
+        self.info.icc_profile = None;
+        self.info.gamma = None;
+        self.info.chromaticities = None;
+        self.info.srgb = Some(self.info.srgb.unwrap_or(RenderingIntent::Perceptual));
+        Ok(())
+    }
+}
+
+// Re-encodes every pixel as sRGB. `to_linear` decodes channel `c` of a sample in 0..=1 to linear light,
+// `matrix` then maps linear RGB to linear sRGB. Alpha is left alone.
+pub fn convert_to_srgb(image: &mut DecodedPng, to_linear: impl Fn(usize, f64) -> f64, matrix: Option<Matrix>) {
+    let convert = |rgb: [f64; 3]| {
+        let linear = [0, 1, 2].map(|c| to_linear(c, rgb[c]));
+        // White maps to white, so grays skip the matrix and stay exactly gray
+        let mixed = match matrix {
+            Some(matrix) if !(linear[0] == linear[1] && linear[1] == linear[2]) => multiply_vector(&matrix, linear),
+            _ => linear,
+        };
+        mixed.map(|value| linear_to_srgb(value.clamp(0.0, 1.0)))
+    };
+
+    if let Some(rgba16) = image.rgba16.as_mut() {
+        for pixel in rgba16.chunks_exact_mut(4) {
+            let srgb = convert([0, 1, 2].map(|c| pixel[c] as f64 / 65535.0));
+            for c in 0..3 {
+                pixel[c] = (srgb[c] * 65535.0).round() as u16;
+            }
+        }
+        for (sample, sample16) in image.rgba.iter_mut().zip(rgba16.iter()) {
+            *sample = (sample16 >> 8) as u8;
+        }
+        return;
+    }
+
+    for pixel in image.rgba.chunks_exact_mut(4) {
+        let srgb = convert([0, 1, 2].map(|c| pixel[c] as f64 / 255.0));
+        for c in 0..3 {
+            pixel[c] = (srgb[c] * 255.0).round() as u8;
+        }
+    }
+}
+
+// https://www.w3.org/Graphics/Color/srgb
+pub fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045 { value / 12.92 } else { ((value + 0.055) / 1.055).powf(2.4) }
+}
+
+pub fn linear_to_srgb(value: f64) -> f64 {
+    if value <= 0.0031308 { value * 12.92 } else { 1.055 * value.powf(1.0 / 2.4) - 0.055 }
+}
+
+// Linear RGB with these primaries to linear sRGB, adapting the white point to D65
+pub fn conversion_to_srgb(chromaticities: &Chromaticities) -> Result<Matrix> {
+    let source = rgb_to_xyz(chromaticities)?;
+    let srgb = rgb_to_xyz(&SRGB_CHROMATICITIES)?;
+    let adapt = bradford(xy_to_xyz(chromaticities.white), xy_to_xyz(SRGB_CHROMATICITIES.white))?;
+    Ok(multiply(&invert(&srgb)?, &multiply(&adapt, &source)))
+}
+
+// RGB to XYZ matrix for a set of primaries, scaled so RGB white lands on the white point with Y = 1
+pub fn rgb_to_xyz(chromaticities: &Chromaticities) -> Result<Matrix> {
+    let [r, g, b] = [chromaticities.red, chromaticities.green, chromaticities.blue].map(xy_to_xyz);
+    let primaries = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+    let scale = multiply_vector(&invert(&primaries)?, xy_to_xyz(chromaticities.white));
+    Ok(primaries.map(|row| [row[0] * scale[0], row[1] * scale[1], row[2] * scale[2]]))
+}
+
+// Maps XYZ colors seen under the `from` white to how they look under `to`
+pub fn bradford(from: [f64; 3], to: [f64; 3]) -> Result<Matrix> {
+    let from = multiply_vector(&BRADFORD, from);
+    let to = multiply_vector(&BRADFORD, to);
+    let scale = [[to[0] / from[0], 0.0, 0.0], [0.0, to[1] / from[1], 0.0], [0.0, 0.0, to[2] / from[2]]];
+    Ok(multiply(&invert(&BRADFORD)?, &multiply(&scale, &BRADFORD)))
+}
+
+// cHRM coordinates are scaled by 100000
+fn xy_to_xyz((x, y): (u32, u32)) -> [f64; 3] {
+    let (x, y) = (x as f64 / 100000.0, y as f64 / 100000.0);
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut product = [[0.0; 3]; 3];
+    for (i, row) in product.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    product
+}
+
+pub fn multiply_vector(matrix: &Matrix, vector: [f64; 3]) -> [f64; 3] {
+    matrix.map(|row| row[0] * vector[0] + row[1] * vector[1] + row[2] * vector[2])
+}
+
+pub fn invert(m: &Matrix) -> Result<Matrix> {
+    let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let adjugate = [
+        [cofactor(1, 2, 1, 2), -cofactor(0, 2, 1, 2), cofactor(0, 1, 1, 2)],
+        [-cofactor(1, 2, 0, 2), cofactor(0, 2, 0, 2), -cofactor(0, 1, 0, 2)],
+        [cofactor(1, 2, 0, 1), -cofactor(0, 2, 0, 1), cofactor(0, 1, 0, 1)],
+    ];
+    let determinant = m[0][0] * adjugate[0][0] + m[0][1] * adjugate[1][0] + m[0][2] * adjugate[2][0];
+    if determinant.abs() < 1e-12 {
+        return Err(PngError::Malformed("Color primaries don't span a color space".to_string()));
+    }
+    Ok(adjugate.map(|row| row.map(|value| value / determinant)))
+}
@@ -2,4 +2,34 @@
 pub const PNG_SIG: [u8; 8] = [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
 pub const IHDR: [u8; 4] = [0x49, 0x48, 0x44, 0x52];
 pub const IDAT: [u8; 4] = [0x49, 0x44, 0x41, 0x54];
-pub const IEND: [u8; 4] = [0x49, 0x45, 0x4e, 0x44];
\ No newline at end of file
+pub const IEND: [u8; 4] = [0x49, 0x45, 0x4e, 0x44];
+pub const PLTE: [u8; 4] = [0x50, 0x4c, 0x54, 0x45];
+pub const TRNS: [u8; 4] = [0x74, 0x52, 0x4e, 0x53];
+
+// Ancillary chunks with placement rules https://www.w3.org/TR/png-3/#5ChunkOrdering
+pub const CHRM: [u8; 4] = [0x63, 0x48, 0x52, 0x4d];
+pub const GAMA: [u8; 4] = [0x67, 0x41, 0x4d, 0x41];
+pub const ICCP: [u8; 4] = [0x69, 0x43, 0x43, 0x50];
+pub const SBIT: [u8; 4] = [0x73, 0x42, 0x49, 0x54];
+pub const SRGB: [u8; 4] = [0x73, 0x52, 0x47, 0x42];
+pub const CICP: [u8; 4] = [0x63, 0x49, 0x43, 0x50];
+pub const MDCV: [u8; 4] = [0x6d, 0x44, 0x43, 0x56];
+pub const CLLI: [u8; 4] = [0x63, 0x4c, 0x4c, 0x49];
+pub const BKGD: [u8; 4] = [0x62, 0x4b, 0x47, 0x44];
+pub const HIST: [u8; 4] = [0x68, 0x49, 0x53, 0x54];
+pub const PHYS: [u8; 4] = [0x70, 0x48, 0x59, 0x73];
+pub const SPLT: [u8; 4] = [0x73, 0x50, 0x4c, 0x54];
+pub const EXIF: [u8; 4] = [0x65, 0x58, 0x49, 0x66];
+pub const ACTL: [u8; 4] = [0x61, 0x63, 0x54, 0x4c];
+
+// Animation frames https://www.w3.org/TR/png-3/#fcTL-chunk
+pub const FCTL: [u8; 4] = [0x66, 0x63, 0x54, 0x4c];
+pub const FDAT: [u8; 4] = [0x66, 0x64, 0x41, 0x54];
+
+// Animation chunks that start with a sequence number, it stays readable in encrypted files so the order can be checked
+pub const SEQUENCED_CHUNKS: [[u8; 4]; 2] = [FCTL, FDAT];
+
+// Textual information https://www.w3.org/TR/png-3/#11textinfo
+pub const TEXT: [u8; 4] = [0x74, 0x45, 0x58, 0x74];
+pub const ZTXT: [u8; 4] = [0x7a, 0x54, 0x58, 0x74];
+pub const ITXT: [u8; 4] = [0x69, 0x54, 0x58, 0x74];
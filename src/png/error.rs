@@ -0,0 +1,66 @@
+use std::fmt;
+use std::io;
+use crate::png::types::CrcMismatch;
+use crate::png::zlib::Adler32Mismatch;
+
+#[derive(Debug)]
+pub enum PngError {
+    // The first 8 bytes aren't the PNG signature
+    BadSignature,
+    BadCrc(CrcMismatch),
+    BadAdler32(Adler32Mismatch),
+    // The data ended while reading the named item
+    Truncated(String),
+    // Valid PNG that uses something this decoder or encoder doesn't handle
+    Unsupported(String),
+    // Data that breaks the PNG spec: bad chunk order, out of range values, unknown filter types...
+    Malformed(String),
+    // AES-GCM authentication failed, usually the wrong key
+    Decryption,
+    Encryption,
+    Decompression(String),
+    LimitExceeded(String),
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, PngError>;
+
+impl PngError {
+    // Keeps the path in the message, io::Error on its own doesn't say which file failed
+    pub fn file(e: io::Error, action: &str, path: &str) -> PngError {
+        PngError::Io(io::Error::new(e.kind(), format!("Could not {} file {}: {}", action, path, e)))
+    }
+}
+
+impl fmt::Display for PngError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PngError::BadSignature => write!(f, "Signature doesn't match PNG signature"),
+            PngError::BadCrc(mismatch) => write!(f, "{}", mismatch),
+            PngError::BadAdler32(mismatch) => write!(f, "{}", mismatch),
+            PngError::Truncated(what) => write!(f, "File is truncated, could not read {}", what),
+            PngError::Unsupported(what) => write!(f, "Unsupported: {}", what),
+            PngError::Malformed(what) => write!(f, "Malformed PNG: {}", what),
+            PngError::Decryption => write!(f, "Could not decrypt image data, is the key correct?"),
+            PngError::Encryption => write!(f, "Could not encrypt image data"),
+            PngError::Decompression(what) => write!(f, "Could not decompress image data: {}", what),
+            PngError::LimitExceeded(what) => write!(f, "Limit exceeded: {}", what),
+            PngError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PngError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PngError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PngError {
+    fn from(e: io::Error) -> Self {
+        PngError::Io(e)
+    }
+}
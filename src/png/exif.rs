@@ -0,0 +1,244 @@
+use std::ops::Range;
+use crate::png::error::{PngError, Result};
+use crate::png::types::DecodedPng;
+
+// Tags we look at https://www.cipa.jp/std/documents/e/DC-X008-Translation-2019-E.pdf
+const ORIENTATION: u16 = 0x0112;
+const EXIF_IFD: u16 = 0x8769;
+const GPS_IFD: u16 = 0x8825;
+const CAMERA_SERIAL_NUMBER: u16 = 0xC62F;
+const BODY_SERIAL_NUMBER: u16 = 0xA431;
+const LENS_SERIAL_NUMBER: u16 = 0xA435;
+
+// eXIf holds a TIFF structure starting with its byte order mark https://www.w3.org/TR/png-3/#eXIf
+// It's kept as bytes and edited in place so tags we don't understand come through untouched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exif {
+    pub data: Vec<u8>,
+}
+
+// One 12 byte IFD entry, `position` is where it starts in the TIFF data
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    position: usize,
+    tag: u16,
+    field_type: u16,
+    count: u32,
+}
+
+impl Exif {
+    pub fn parse(data: &[u8]) -> Result<Exif> {
+        if !data.starts_with(b"II*\0") && !data.starts_with(b"MM\0*") {
+            return Err(malformed("eXIf doesn't start with a TIFF header"));
+        }
+        let exif = Exif { data: data.to_vec() };
+        exif.first_ifd().and_then(|ifd| exif.entries(ifd)).ok_or_else(|| malformed("eXIf IFD0 runs past the end of the chunk"))?;
+        Ok(exif)
+    }
+
+    // 1 is upright, 2-8 are the mirrored and rotated variants
+    pub fn orientation(&self) -> Option<u16> {
+        let entry = self.find(self.first_ifd()?, ORIENTATION)?;
+        self.u16_at(entry.position + 8).filter(|value| (1..=8).contains(value))
+    }
+
+    pub fn set_orientation(&mut self, orientation: u16) {
+        if let Some(entry) = self.first_ifd().and_then(|ifd| self.find(ifd, ORIENTATION)) {
+            self.put_u16(entry.position + 8, orientation);
+        }
+    }
+
+    pub fn has_location(&self) -> bool {
+        self.first_ifd().and_then(|ifd| self.find(ifd, GPS_IFD)).is_some()
+    }
+
+    // Removes the GPS IFD and camera/lens serial numbers, zeroing their values so nothing is left behind
+    // in the unused space. Returns how many tags were removed.
+    pub fn redact(&mut self) -> usize {
+        let Some(ifd0) = self.first_ifd() else {
+            return 0;
+        };
+        let mut removed = 0;
+        let exif_ifd = self.find(ifd0, EXIF_IFD).and_then(|entry| self.u32_at(entry.position + 8)).map(|offset| offset as usize);
+
+        if let Some(gps) = self.find(ifd0, GPS_IFD) {
+            // A crafted GPS pointer can lead back into IFD0 or the Exif IFD, zeroing it would take those with it
+            let others: Vec<_> = [Some(ifd0), exif_ifd].into_iter().flatten().filter_map(|ifd| self.ifd_range(ifd)).collect();
+            if let Some(gps_ifd) = self.u32_at(gps.position + 8).map(|offset| offset as usize)
+                && let Some(range) = self.ifd_range(gps_ifd)
+                && !others.iter().any(|other| overlaps(other, &range))
+                && let Some(entries) = self.entries(gps_ifd) {
+                for entry in &entries {
+                    self.zero_value(entry);
+                }
+                removed += entries.len();
+                self.zero(range);
+            }
+            self.remove(ifd0, gps);
+            removed += 1;
+        }
+
+        for (ifd, tag) in [(Some(ifd0), CAMERA_SERIAL_NUMBER), (exif_ifd, BODY_SERIAL_NUMBER), (exif_ifd, LENS_SERIAL_NUMBER)] {
+            if let Some(ifd) = ifd && let Some(entry) = self.find(ifd, tag) {
+                self.remove(ifd, entry);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn big_endian(&self) -> bool {
+        self.data[0] == b'M'
+    }
+
+    fn first_ifd(&self) -> Option<usize> {
+        self.u32_at(4).map(|offset| offset as usize)
+    }
+
+    fn entries(&self, ifd: usize) -> Option<Vec<Entry>> {
+        let count = self.u16_at(ifd)? as usize;
+        // The entries are followed by the offset of the next IFD
+        if ifd + 2 + count * 12 + 4 > self.data.len() {
+            return None;
+        }
+        (0..count).map(|i| {
+            let position = ifd + 2 + i * 12;
+            Some(Entry { position, tag: self.u16_at(position)?, field_type: self.u16_at(position + 2)?, count: self.u32_at(position + 4)? })
+        }).collect()
+    }
+
+    // The count, entries and next IFD offset
+    fn ifd_range(&self, ifd: usize) -> Option<Range<usize>> {
+        let count = self.entries(ifd)?.len();
+        Some(ifd..ifd + 2 + count * 12 + 4)
+    }
+
+    fn find(&self, ifd: usize, tag: u16) -> Option<Entry> {
+        self.entries(ifd)?.into_iter().find(|entry| entry.tag == tag)
+    }
+
+    // Values of up to 4 bytes sit in the entry itself, larger ones are at an offset
+    fn value_range(&self, entry: &Entry) -> Option<Range<usize>> {
+        let size = type_size(entry.field_type)?.checked_mul(entry.count as usize)?;
+        let start = if size <= 4 { entry.position + 8 } else { self.u32_at(entry.position + 8)? as usize };
+        let end = start.checked_add(size)?;
+        (end <= self.data.len()).then_some(start..end)
+    }
+
+    fn zero_value(&mut self, entry: &Entry) {
+        if let Some(range) = self.value_range(entry) {
+            self.zero(range);
+        }
+    }
+
+    // Zeroes the value and shifts the following entries and next IFD offset down over the entry
+    // Zeroes the value and shifts the following entries and next IFD offset down over the entry. The count is read
+    // again and the entry checked against it, an earlier edit may have changed the IFD under it.
+    fn remove(&mut self, ifd: usize, entry: Entry) {
+        let (Some(range), Some(count)) = (self.ifd_range(ifd), self.u16_at(ifd).and_then(|count| count.checked_sub(1))) else {
+            return;
+        };
+        let in_ifd = entry.position.checked_sub(ifd + 2).is_some_and(|offset| offset % 12 == 0)
+            && entry.position.checked_add(12).is_some_and(|entry_end| entry_end <= range.end - 4);
+        if !in_ifd || self.u16_at(entry.position) != Some(entry.tag) {
+            return;
+        }
+        self.zero_value(&entry);
+        self.data.copy_within(entry.position + 12..range.end, entry.position);
+        self.zero(range.end - 12..range.end);
+        self.put_u16(ifd, count);
+    }
+
+    fn zero(&mut self, range: Range<usize>) {
+        let end = range.end.min(self.data.len());
+        if range.start < end {
+            self.data[range.start..end].fill(0);
+        }
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = [*self.data.get(offset)?, *self.data.get(offset + 1)?];
+        Some(if self.big_endian() { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset.checked_add(4)?)?.try_into().ok()?;
+        Some(if self.big_endian() { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn put_u16(&mut self, offset: usize, value: u16) {
+        let bytes = if self.big_endian() { value.to_be_bytes() } else { value.to_le_bytes() };
+        self.data[offset..offset + 2].copy_from_slice(&bytes);
+    }
+}
+
+impl DecodedPng {
+    // Rotates and mirrors the pixels the way the EXIF orientation asks viewers to, then marks them upright
+    pub fn apply_orientation(&mut self) {
+        let Some(orientation) = self.info.exif.as_ref().and_then(Exif::orientation) else {
+            return;
+        };
+        if orientation == 1 {
+            return;
+        }
+
+        let (width, height) = (self.info.width as usize, self.info.height as usize);
+        self.pixels_modified = true;
+        self.rgba = reorient(&self.rgba, width, height, orientation);
+        if let Some(rgba16) = &self.rgba16 {
+            self.rgba16 = Some(reorient(rgba16, width, height, orientation));
+        }
+        // 5-8 turn the image on its side
+        if orientation >= 5 {
+            std::mem::swap(&mut self.info.width, &mut self.info.height);
+            if let Some(physical) = self.info.physical.as_mut() {
+                std::mem::swap(&mut physical.x, &mut physical.y);
+            }
+        }
+        if let Some(exif) = self.info.exif.as_mut() {
+            exif.set_orientation(1);
+        }
+    }
+}
+
+// RGBA pixels of a `width` x `height` image as they should be displayed for `orientation`
+fn reorient<T: Copy>(pixels: &[T], width: usize, height: usize, orientation: u16) -> Vec<T> {
+    let (out_width, out_height) = if orientation >= 5 { (height, width) } else { (width, height) };
+    let mut output = Vec::with_capacity(pixels.len());
+    for y in 0..out_height {
+        for x in 0..out_width {
+            let (source_x, source_y) = match orientation {
+                2 => (width - 1 - x, y),
+                3 => (width - 1 - x, height - 1 - y),
+                4 => (x, height - 1 - y),
+                5 => (y, x),
+                6 => (y, height - 1 - x),
+                7 => (width - 1 - y, height - 1 - x),
+                8 => (width - 1 - y, x),
+                _ => (x, y),
+            };
+            let i = (source_y * width + source_x) * 4;
+            output.extend_from_slice(&pixels[i..i + 4]);
+        }
+    }
+    output
+}
+
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+// Bytes per value of each TIFF field type
+fn type_size(field_type: u16) -> Option<usize> {
+    match field_type {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+fn malformed(message: &str) -> PngError {
+    PngError::Malformed(message.to_string())
+}
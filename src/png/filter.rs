@@ -1,6 +1,8 @@
+use crate::png::error::{PngError, Result};
+
 // Basically the opposite of the method below lol https://www.w3.org/TR/png-3/#9Filters
-pub fn apply_filter(filter_type: u8, bytes_per_pixel: usize, row: &[u8], prev_row: Option<&[u8]>) -> Vec<u8> {
-    match filter_type {
+pub fn apply_filter(filter_type: u8, bytes_per_pixel: usize, row: &[u8], prev_row: Option<&[u8]>) -> Result<Vec<u8>> {
+    let filtered = match filter_type {
         0 => {
             // None: Filt(x) = Orig(x)
             row.to_vec()
@@ -50,13 +52,23 @@ pub fn apply_filter(filter_type: u8, bytes_per_pixel: usize, row: &[u8], prev_ro
             filtered
         },
         _ => {
-            panic!("Unsupported filter type: {}", filter_type);
+            return Err(unknown_filter(filter_type));
         }
-    }
+    };
+
+    Ok(filtered)
 }
 
-pub fn unfilter_row(filter_type: u8, bytes_per_pixel: usize, src: &[u8], prev: Option<Vec<u8>>, dst: &mut [u8]){
-    let prev = prev.unwrap_or(vec![0u8; src.len()]);
+pub fn unfilter_row(filter_type: u8, bytes_per_pixel: usize, src: &[u8], prev: Option<&[u8]>, dst: &mut [u8]) -> Result<()> {
+    // The first row of an image (or pass) is filtered against a row of zeros
+    let zeros;
+    let prev = match prev {
+        Some(prev) => prev,
+        None => {
+            zeros = vec![0u8; src.len()];
+            &zeros
+        }
+    };
 
     match filter_type {
         0 => { // None
@@ -93,9 +105,15 @@ pub fn unfilter_row(filter_type: u8, bytes_per_pixel: usize, src: &[u8], prev: O
             }
         }
         _ => {
-            panic!("Unsupported filter type: {}", filter_type);
+            return Err(unknown_filter(filter_type));
         }
     }
+
+    Ok(())
+}
+
+fn unknown_filter(filter_type: u8) -> PngError {
+    PngError::Malformed(format!("Unknown filter type {}", filter_type))
 }
 
 fn paeth_predictor(a: u8, b: u8, c: u8) -> u8{
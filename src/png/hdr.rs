@@ -0,0 +1,162 @@
+use crate::png::color::{conversion_to_srgb, convert_to_srgb, srgb_to_linear, SRGB_CHROMATICITIES};
+use crate::png::error::{PngError, Result};
+use crate::png::types::*;
+
+// Luminance SDR white is placed at when tone mapping, in cd/m² https://www.itu.int/pub/R-REP-BT.2408
+const SDR_WHITE: f64 = 203.0;
+// Nominal peak of an HLG display, the HLG system gamma of 1.2 is defined for it https://www.itu.int/rec/R-REC-BT.2100
+const HLG_PEAK: f64 = 1000.0;
+
+// https://www.w3.org/TR/png-3/#cICP-chunk
+pub fn parse_cicp(data: &[u8]) -> Result<Cicp> {
+    let &[colour_primaries, transfer_function, matrix_coefficients, full_range] = data else {
+        return Err(PngError::Malformed(format!("cICP length is {}, expected 4", data.len())));
+    };
+    if matrix_coefficients != 0 {
+        return Err(PngError::Malformed(format!("cICP matrix coefficients {} are not RGB", matrix_coefficients)));
+    }
+    if full_range > 1 {
+        return Err(PngError::Malformed(format!("cICP full range flag {} is invalid", full_range)));
+    }
+    Ok(Cicp { colour_primaries, transfer_function, matrix_coefficients, full_range: full_range == 1 })
+}
+
+// https://www.w3.org/TR/png-3/#mDCV-chunk
+pub fn parse_mastering_display(data: &[u8]) -> Result<MasteringDisplay> {
+    if data.len() != 24 {
+        return Err(PngError::Malformed(format!("mDCV length is {}, expected 24", data.len())));
+    }
+    let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+    let u32_at = |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    Ok(MasteringDisplay {
+        primaries: [(u16_at(0), u16_at(2)), (u16_at(4), u16_at(6)), (u16_at(8), u16_at(10))],
+        white: (u16_at(12), u16_at(14)),
+        max_luminance: u32_at(16),
+        min_luminance: u32_at(20),
+    })
+}
+
+// https://www.w3.org/TR/png-3/#cLLI-chunk
+pub fn parse_content_light_level(data: &[u8]) -> Result<ContentLightLevel> {
+    if data.len() != 8 {
+        return Err(PngError::Malformed(format!("cLLI length is {}, expected 8", data.len())));
+    }
+    Ok(ContentLightLevel {
+        max_cll: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+        max_fall: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+    })
+}
+
+impl DecodedPng {
+    // Lossy levels quantize the samples, which crushes the range PQ and HLG spread over them. The encoders don't
+    // check this themselves, callers should before encoding an HDR image at a lossy level.
+    pub fn check_lossy_hdr(&self, compression_level: &CompressionLevel) -> Result<()> {
+        if !matches!(compression_level, CompressionLevel::Lossless) && self.info.cicp.is_some_and(|cicp| cicp.is_hdr()) {
+            return Err(PngError::Unsupported("lossy compression of an HDR (PQ/HLG) image".to_string()));
+        }
+        Ok(())
+    }
+
+    // Maps PQ or HLG content to SDR sRGB. Light is measured relative to SDR reference white and compressed
+    // with an extended Reinhard curve that puts the content's peak at sRGB white. The HDR chunks are
+    // replaced by an sRGB chunk.
+    pub fn tone_map_to_sdr(&mut self) -> Result<()> {
+        let cicp = self.info.cicp.filter(Cicp::is_hdr)
+            .ok_or_else(|| PngError::Unsupported("tone mapping without PQ or HLG cICP".to_string()))?;
+        let primaries = cicp_primaries(cicp.colour_primaries)?;
+        let (offset, scale) = signal_range(&cicp);
+
+        let peak = match self.info.content_light_level {
+            Some(level) if level.max_cll > 0 => level.max_cll as f64 / 10000.0,
+            _ => match self.info.mastering_display {
+                Some(display) if display.max_luminance > 0 => display.max_luminance as f64 / 10000.0,
+                _ if cicp.transfer_function == TRANSFER_PQ => 10000.0,
+                _ => HLG_PEAK,
+            },
+        };
+        let white = (peak / SDR_WHITE).max(1.0);
+        let matrix = (primaries != SRGB_CHROMATICITIES).then(|| conversion_to_srgb(&primaries)).transpose()?;
+
+        convert_to_srgb(self, |_, value| {
+            let signal = ((value - offset) / scale).clamp(0.0, 1.0);
+            let nits = match cicp.transfer_function {
+                TRANSFER_PQ => pq_to_nits(signal),
+                _ => hlg_to_nits(signal),
+            };
+            let x = nits / SDR_WHITE;
+            x * (1.0 + x / (white * white)) / (1.0 + x)
+        }, matrix);
+        self.pixels_modified = true;
+
+        self.info.cicp = None;
+        self.info.mastering_display = None;
+        self.info.content_light_level = None;
+        self.info.icc_profile = None;
+        self.info.gamma = None;
+        self.info.chromaticities = None;
+        self.info.srgb = Some(RenderingIntent::Perceptual);
+        Ok(())
+    }
+
+    // Converts SDR content tagged with cICP to sRGB pixels. The caller replaces the color chunks.
+    pub fn convert_cicp_to_srgb(&mut self) -> Result<()> {
+        let Some(cicp) = self.info.cicp.filter(|cicp| !cicp.is_hdr()) else {
+            return Err(PngError::Unsupported("SDR conversion of a PQ or HLG image, it needs tone mapping".to_string()));
+        };
+        let primaries = cicp_primaries(cicp.colour_primaries)?;
+        let to_linear = sdr_transfer(cicp.transfer_function)?;
+        let (offset, scale) = signal_range(&cicp);
+        let matrix = (primaries != SRGB_CHROMATICITIES).then(|| conversion_to_srgb(&primaries)).transpose()?;
+        convert_to_srgb(self, |_, value| to_linear(((value - offset) / scale).clamp(0.0, 1.0)), matrix);
+        self.pixels_modified = true;
+        Ok(())
+    }
+}
+
+// Samples outside the narrow range are clipped, as in video
+fn signal_range(cicp: &Cicp) -> (f64, f64) {
+    if cicp.full_range { (0.0, 1.0) } else { (16.0 / 255.0, 219.0 / 255.0) }
+}
+
+// H.273 TransferCharacteristics code points for SDR content, as functions from signal to linear light
+fn sdr_transfer(code: u8) -> Result<fn(f64) -> f64> {
+    match code {
+        // BT.709, BT.601 and BT.2020 share one curve
+        1 | 6 | 14 | 15 => Ok(|signal| if signal < 0.081 { signal / 4.5 } else { ((signal + 0.099) / 1.099).powf(1.0 / 0.45) }),
+        4 => Ok(|signal| signal.powf(2.2)),
+        5 => Ok(|signal| signal.powf(2.8)),
+        8 => Ok(|signal| signal),
+        13 => Ok(srgb_to_linear),
+        _ => Err(PngError::Unsupported(format!("cICP transfer characteristics {}", code))),
+    }
+}
+
+// H.273 ColourPrimaries code points we can convert from, all with a D65 white
+fn cicp_primaries(code: u8) -> Result<Chromaticities> {
+    match code {
+        1 => Ok(SRGB_CHROMATICITIES),
+        9 => Ok(Chromaticities { white: (31270, 32900), red: (70800, 29200), green: (17000, 79700), blue: (13100, 4600) }),
+        12 => Ok(Chromaticities { white: (31270, 32900), red: (68000, 32000), green: (26500, 69000), blue: (15000, 6000) }),
+        _ => Err(PngError::Unsupported(format!("cICP colour primaries {}", code))),
+    }
+}
+
+// SMPTE ST 2084 EOTF, a signal in 0..=1 to cd/m²
+fn pq_to_nits(signal: f64) -> f64 {
+    const M1: f64 = 2610.0 / 16384.0;
+    const M2: f64 = 2523.0 / 4096.0 * 128.0;
+    const C1: f64 = 3424.0 / 4096.0;
+    const C2: f64 = 2413.0 / 4096.0 * 32.0;
+    const C3: f64 = 2392.0 / 4096.0 * 32.0;
+    let p = signal.powf(1.0 / M2);
+    10000.0 * ((p - C1).max(0.0) / (C2 - C3 * p)).powf(1.0 / M1)
+}
+
+// BT.2100 HLG inverse OETF followed by the OOTF of a nominal 1000 cd/m² display, applied per channel
+fn hlg_to_nits(signal: f64) -> f64 {
+    const A: f64 = 0.17883277;
+    const B: f64 = 0.28466892;
+    const C: f64 = 0.55991073;
+    let scene = if signal <= 0.5 { signal * signal / 3.0 } else { (((signal - C) / A).exp() + B) / 12.0 };
+    HLG_PEAK * scene.powf(1.2)
+}
@@ -0,0 +1,192 @@
+use std::io::Write;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use crate::png::color::Matrix;
+use crate::png::error::{PngError, Result};
+use crate::png::text::check_keyword;
+use crate::png::types::DecodedPng;
+use crate::png::zlib::inflate;
+
+// PCS illuminant every ICC profile is relative to https://www.color.org/specification/ICC.1-2022-05.pdf
+pub const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+// Embedded profile from iCCP, `data` is the uncompressed ICC profile https://www.w3.org/TR/png-3/#11iCCP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IccProfile {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl IccProfile {
+    pub fn new(name: &str, data: Vec<u8>) -> Result<IccProfile> {
+        check_keyword(name)?;
+        // 7.2 every profile starts with a 128 byte header with 'acsp' at offset 36
+        if data.len() < 128 || &data[36..40] != b"acsp" {
+            return Err(malformed("Not an ICC profile"));
+        }
+        Ok(IccProfile { name: name.to_string(), data })
+    }
+
+    pub fn parse(data: &[u8], max_profile_bytes: u64) -> Result<IccProfile> {
+        let end = data.iter().position(|&b| b == 0).ok_or_else(|| malformed("iCCP profile name is not null terminated"))?;
+        let name: String = data[..end].iter().map(|&b| b as char).collect();
+        let Some((&method, compressed)) = data[end + 1..].split_first() else {
+            return Err(malformed("iCCP is missing its compression method"));
+        };
+        if method != 0 {
+            return Err(PngError::Unsupported(format!("iCCP compression method {}", method)));
+        }
+        let (profile, adler32_mismatch) = inflate(compressed, max_profile_bytes)?;
+        if let Some(mismatch) = adler32_mismatch {
+            return Err(PngError::BadAdler32(mismatch));
+        }
+        IccProfile::new(&name, profile)
+    }
+
+    pub fn to_chunk_data(&self) -> Result<Vec<u8>> {
+        let mut data: Vec<u8> = self.name.chars().map(|c| c as u8).collect();
+        data.extend_from_slice(&[0, 0]);
+        let mut encoder = ZlibEncoder::new(data, Compression::best());
+        encoder.write_all(&self.data)?;
+        Ok(encoder.finish()?)
+    }
+
+    // Tone curves for each channel and the linear RGB to PCS XYZ matrix of a matrix/TRC profile.
+    // Gray profiles have no matrix, their gray axis already is the white point.
+    pub fn matrix_trc(&self) -> Result<([Curve; 3], Option<Matrix>)> {
+        let profile = &self.data;
+        match &profile[16..20] {
+            b"RGB " => {
+                let curves = [b"rTRC", b"gTRC", b"bTRC"].map(|tag| Curve::parse(profile, tag));
+                let [red, green, blue] = [b"rXYZ", b"gXYZ", b"bXYZ"].map(|tag| parse_xyz(profile, tag));
+                let (red, green, blue) = (red?, green?, blue?);
+                let matrix = [
+                    [red[0], green[0], blue[0]],
+                    [red[1], green[1], blue[1]],
+                    [red[2], green[2], blue[2]],
+                ];
+                let [r, g, b] = curves;
+                Ok(([r?, g?, b?], Some(matrix)))
+            },
+            b"GRAY" => {
+                let curve = Curve::parse(profile, b"kTRC")?;
+                Ok(([curve.clone(), curve.clone(), curve], None))
+            },
+            space => Err(PngError::Unsupported(format!("ICC profiles for the {} color space", String::from_utf8_lossy(space).trim()))),
+        }
+    }
+}
+
+impl DecodedPng {
+    // 11.3.2.4 iCCP and sRGB shouldn't both be present, the profile wins
+    pub fn set_icc_profile(&mut self, profile: IccProfile) {
+        self.info.srgb = None;
+        self.info.icc_profile = Some(profile);
+    }
+}
+
+// A tone reproduction curve, maps an encoded sample in 0..=1 to linear light
+#[derive(Debug, Clone, PartialEq)]
+pub enum Curve {
+    Gamma(f64),
+    Table(Vec<u16>),
+    // parametricCurveType function type and its parameters (g, a, b, c, d, e, f)
+    Parametric(u16, [f64; 7]),
+}
+
+impl Curve {
+    fn parse(profile: &[u8], tag: &[u8; 4]) -> Result<Curve> {
+        let data = find_tag(profile, tag)?;
+        match data.get(..4) {
+            // 10.6 curveType
+            Some(b"curv") => {
+                let count = read_u32(data, 8)? as usize;
+                match count {
+                    0 => Ok(Curve::Gamma(1.0)),
+                    1 => Ok(Curve::Gamma(read_u16(data, 12)? as f64 / 256.0)),
+                    _ => (0..count).map(|i| read_u16(data, 12 + i * 2)).collect::<Result<Vec<u16>>>().map(Curve::Table),
+                }
+            },
+            // 10.18 parametricCurveType
+            Some(b"para") => {
+                let function = read_u16(data, 8)?;
+                let count = match function {
+                    0 => 1,
+                    1 => 3,
+                    2 => 4,
+                    3 => 5,
+                    4 => 7,
+                    _ => return Err(PngError::Unsupported(format!("ICC parametric curve type {}", function))),
+                };
+                let mut params = [0.0; 7];
+                for (i, param) in params.iter_mut().take(count).enumerate() {
+                    *param = read_s15_fixed16(data, 12 + i * 4)?;
+                }
+                Ok(Curve::Parametric(function, params))
+            },
+            _ => Err(PngError::Unsupported(format!("ICC {} tag type", String::from_utf8_lossy(tag)))),
+        }
+    }
+
+    pub fn eval(&self, x: f64) -> f64 {
+        match self {
+            Curve::Gamma(gamma) => x.powf(*gamma),
+            Curve::Table(table) => {
+                let position = x.clamp(0.0, 1.0) * (table.len() - 1) as f64;
+                let i = (position as usize).min(table.len() - 2);
+                let t = position - i as f64;
+                (table[i] as f64 * (1.0 - t) + table[i + 1] as f64 * t) / 65535.0
+            },
+            Curve::Parametric(function, [g, a, b, c, d, e, f]) => match function {
+                0 => x.powf(*g),
+                1 if x >= -b / a => (a * x + b).powf(*g),
+                1 => 0.0,
+                2 if x >= -b / a => (a * x + b).powf(*g) + c,
+                2 => *c,
+                3 if x >= *d => (a * x + b).powf(*g),
+                3 => c * x,
+                _ if x >= *d => (a * x + b).powf(*g) + e,
+                _ => c * x + f,
+            },
+        }
+    }
+}
+
+// Looks a tag up in the tag table that follows the header
+fn find_tag<'a>(profile: &'a [u8], tag: &[u8; 4]) -> Result<&'a [u8]> {
+    let count = read_u32(profile, 128)? as usize;
+    for i in 0..count.min(profile.len() / 12) {
+        let entry = 132 + i * 12;
+        if profile.get(entry..entry + 4) == Some(&tag[..]) {
+            let offset = read_u32(profile, entry + 4)? as usize;
+            let size = read_u32(profile, entry + 8)? as usize;
+            return profile.get(offset..offset.saturating_add(size)).ok_or_else(|| malformed("ICC tag runs past the end of the profile"));
+        }
+    }
+    Err(PngError::Unsupported(format!("ICC profiles without a {} tag", String::from_utf8_lossy(tag))))
+}
+
+// 10.31 XYZType
+fn parse_xyz(profile: &[u8], tag: &[u8; 4]) -> Result<[f64; 3]> {
+    let data = find_tag(profile, tag)?;
+    if data.get(..4) != Some(b"XYZ ") {
+        return Err(malformed("ICC colorant tag is not XYZType"));
+    }
+    Ok([read_s15_fixed16(data, 8)?, read_s15_fixed16(data, 12)?, read_s15_fixed16(data, 16)?])
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or_else(|| malformed("ICC profile is truncated"))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]])).ok_or_else(|| malformed("ICC profile is truncated"))
+}
+
+fn read_s15_fixed16(data: &[u8], offset: usize) -> Result<f64> {
+    Ok(read_u32(data, offset)? as i32 as f64 / 65536.0)
+}
+
+fn malformed(message: &str) -> PngError {
+    PngError::Malformed(message.to_string())
+}
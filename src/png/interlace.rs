@@ -0,0 +1,49 @@
+// Adam7 pass origins and steps (x, y, dx, dy) https://www.w3.org/TR/png-3/#8Interlace
+pub const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+// A reduced image making up part of the full image, pixel (px, py) of the pass
+// lands on (x + px * dx, y + py * dy) of the full image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pass {
+    pub x: usize,
+    pub y: usize,
+    pub dx: usize,
+    pub dy: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Pass {
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn image_index(&self, px: usize, py: usize, image_width: usize) -> usize {
+        (self.y + py * self.dy) * image_width + self.x + px * self.dx
+    }
+}
+
+// Passes in the order their scanlines appear in the image data. A non-interlaced image is a single pass.
+// Empty passes are kept so callers can index them, they contribute no scanlines (not even filter bytes).
+pub fn image_passes(interlace: u8, width: usize, height: usize) -> Vec<Pass> {
+    if interlace == 0 {
+        return vec![Pass { x: 0, y: 0, dx: 1, dy: 1, width, height }];
+    }
+
+    ADAM7.iter().map(|&(x, y, dx, dy)| Pass {
+        x,
+        y,
+        dx,
+        dy,
+        width: width.saturating_sub(x).div_ceil(dx),
+        height: height.saturating_sub(y).div_ceil(dy),
+    }).collect()
+}
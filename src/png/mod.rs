@@ -4,6 +4,17 @@ pub mod read;
 pub mod write;
 pub mod filter;
 pub mod optimization;
+pub mod interlace;
+pub mod zlib;
+pub mod error;
+pub mod text;
+pub mod color;
+pub mod icc;
+pub mod exif;
+pub mod background;
+pub mod hdr;
+pub mod apng;
+pub mod stream;
 
 pub use types::*;
 
@@ -1,14 +1,23 @@
-use std::io::{Read, Cursor};
+use std::io::{BufReader, Cursor, Read};
 use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
-use aes_gcm::aead::Aead;
-use anyhow::{bail, Context, Result};
+use aes_gcm::aead::{Aead, Payload};
+use crate::png::error::{PngError, Result};
 use byteorder::{BigEndian, ReadBytesExt};
-use flate2::read::ZlibDecoder;
+use crc32fast::Hasher;
 use indicatif::ProgressBar;
 use crate::png::types::*;
 use crate::png::constants::*;
 use crate::png::filter::unfilter_row;
+use crate::png::interlace::{image_passes, Pass};
 use crate::png::parse_image_type;
+use crate::png::color::{parse_chromaticities, parse_gamma, parse_srgb};
+use crate::png::background::parse_background;
+use crate::png::exif::Exif;
+use crate::png::hdr::{parse_cicp, parse_content_light_level, parse_mastering_display};
+use crate::png::icc::IccProfile;
+use crate::png::stream::StreamingDecoder;
+use crate::png::text::{is_text_chunk, TextChunk};
+use crate::png::zlib::{inflate, inflate_partial};
 
 impl DecodedPng {
     #[allow(dead_code)]
@@ -27,183 +36,817 @@ impl DecodedPng {
             alpha: self.rgba[base + 3],
         }
     }
-    pub async fn read_from_file_async(path: &str, decryption_key: Option<[u8; 32]>, pb: &ProgressBar) -> Result<DecodedPng> {
-        pb.set_message(format!("Reading image {}", path));
-        let bytes = smol::fs::read(path).await.with_context(|| format!("Could not read file {}", path))?;
+    pub async fn read_from_file_async(path: &str, decryption_key: Option<[u8; 32]>, options: DecodeOptions, pb: &ProgressBar) -> Result<DecodedPng> {
+        let path = path.to_string();
         let pb_clone = pb.clone();
         smol::unblock(move || {
-            Self::from_bytes(&bytes, decryption_key.as_ref(), &pb_clone)
+            Self::read_from_file(&path, decryption_key.as_ref(), &options, &pb_clone)
         }).await
     }
 
-    #[allow(dead_code)]
-    pub fn read_from_file(path: &str, decryption_key: Option<&[u8; 32]>, pb: &ProgressBar) -> Result<DecodedPng> {
+    // Files are streamed through StreamingDecoder, so only the decoded pixels are held in memory. Recovery
+    // needs the whole file decoder, which reads the file in one go.
+    pub fn read_from_file(path: &str, decryption_key: Option<&[u8; 32]>, options: &DecodeOptions, pb: &ProgressBar) -> Result<DecodedPng> {
         pb.set_message(format!("Reading image {}", path));
-        let mut file = std::fs::File::open(path).with_context(|| format!("Could not open file {}", path))?;
+        let mut file = std::fs::File::open(path).map_err(|e| PngError::file(e, "open", path))?;
+        if !options.recover {
+            return StreamingDecoder::new(BufReader::new(file), decryption_key, options.clone())?.decode(pb);
+        }
         let mut bytes: Vec<u8> = Vec::new();
-        file.read_to_end(&mut bytes).with_context(|| format!("Could not read file {}", path))?;
-        Self::from_bytes(&bytes, decryption_key, pb)
+        file.read_to_end(&mut bytes).map_err(|e| PngError::file(e, "read", path))?;
+        Self::from_bytes_with_options(&bytes, decryption_key, options, pb)
     }
 
+    #[allow(dead_code)]
     pub fn from_bytes(bytes: &[u8], decryption_key: Option<&[u8; 32]>, pb: &ProgressBar) -> Result<DecodedPng> {
+        Self::from_bytes_with_options(bytes, decryption_key, &DecodeOptions::default(), pb)
+    }
+
+    pub fn from_bytes_with_options(bytes: &[u8], decryption_key: Option<&[u8; 32]>, options: &DecodeOptions, pb: &ProgressBar) -> Result<DecodedPng> {
         let mut cursor = Cursor::new(bytes);
         let mut signature: [u8; 8] = [0u8; 8];
-        cursor.read_exact(&mut signature).map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        cursor.read_exact(&mut signature).map_err(|_| PngError::BadSignature)?;
 
         if signature != PNG_SIG {
-            bail!("Signature doesn't match PNG signature");
+            return Err(PngError::BadSignature);
         }
 
         let mut info: Option<PngInfo> = None;
         let mut idat_data: Vec<u8> = Vec::new();
+        let mut palette: Option<Vec<[u8; 3]>> = None;
+        let mut transparency: Option<Vec<u8>> = None;
+
+        let mut report = DecodeReport::default();
+        let mut order = ChunkOrder::default();
+
+        let limits = &options.limits;
+        let mut chunk_count = 0u32;
+
+        let recover = options.recover;
+        let crc_mode = if recover { CrcMode::Lenient } else { options.crc_mode };
+        let mut seen_iend = false;
+        let mut chunks: Vec<AncillaryChunk> = Vec::new();
+        let mut text: Vec<TextChunk> = Vec::new();
+        let mut gamma = None;
+        let mut chromaticities = None;
+        let mut srgb = None;
+        let mut icc_profile = None;
+        let mut physical = None;
+        let mut exif = None;
+        let mut background = None;
+        let mut significant_bits = None;
+        let mut cicp = None;
+        let mut mastering_display = None;
+        let mut content_light_level = None;
+        let mut position = ChunkPosition::BeforePlte;
+        // Set once an IDAT chunk is lost in recovery, the zlib stream can't continue past the gap
+        let mut idat_broken = false;
 
         loop {
-            let length = match cursor.read_u32::<BigEndian>() {
-                Ok(length) => length as usize,
-                Err(_) => break,
+            let offset = cursor.position() as usize;
+            let Ok(length) = cursor.read_u32::<BigEndian>() else {
+                break;
             };
 
-            let mut chunk_type: Vec<u8> = vec![0u8; 4];
-            cursor.read_exact(&mut chunk_type).with_context(|| "Could not read chunk type")?;
+            chunk_count += 1;
+            check_chunk_limits(length, chunk_count, limits)?;
+            // Never allocate more than the file could possibly hold (type + data + CRC)
+            let length = length as usize;
+            if length.saturating_add(8) > bytes.len() - cursor.position() as usize {
+                if !recover {
+                    return Err(truncated("chunk data"));
+                }
+
+                // The file ends inside this chunk, keep whatever part of it made it. Encrypted data
+                // can't be authenticated without the rest of the chunk so that is dropped.
+                let mut chunk_type = [0u8; 4];
+                if cursor.read_exact(&mut chunk_type).is_ok() {
+                    let data = &bytes[cursor.position() as usize..];
+                    let data = &data[..data.len().min(length)];
+                    report.damage.push(Damage::TruncatedChunk { chunk_type, offset, length, available: data.len() });
+                    if chunk_type == IDAT && decryption_key.is_none() && !idat_broken && order.check(&chunk_type, info.as_ref()).is_ok() {
+                        idat_data.extend_from_slice(data);
+                    }
+                }
+                break;
+            }
+
+            let mut chunk_type = [0u8; 4];
+            cursor.read_exact(&mut chunk_type).map_err(|_| truncated("chunk type"))?;
 
             let mut data = vec![0u8; length];
-            cursor.read_exact(&mut data).with_context(|| "Could not read data")?;
+            cursor.read_exact(&mut data).map_err(|_| truncated("chunk data"))?;
+
+            let stored_crc = cursor.read_u32::<BigEndian>().map_err(|_| truncated("chunk CRC"))?;
+
+            // 5.3 the CRC covers the chunk type and data but not the length
+            let mut hasher = Hasher::new();
+            hasher.update(&chunk_type);
+            hasher.update(&data);
+            let mismatch = CrcMismatch { chunk_type, offset, stored: stored_crc, computed: hasher.finalize() };
+            verify_crc(mismatch, crc_mode, &mut report)?;
+
+            let placed = match order.check(&chunk_type, info.as_ref()) {
+                Ok(placed) => placed,
+                // A damaged chunk type can look like an unknown critical chunk or one out of order, skip it and keep going
+                Err(e) if recover && info.is_some() => {
+                    report.damage.push(Damage::SkippedChunk { chunk_type, offset, reason: e.to_string() });
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+            if !placed {
+                report.misplaced_chunks.push(MisplacedChunk { chunk_type, offset });
+                continue;
+            }
+            let data = match decrypt_ancillary_chunk(&chunk_type, data, decryption_key) {
+                Ok(data) => data,
+                Err(_) if recover => {
+                    report.damage.push(Damage::UndecryptableChunk { chunk_type, offset });
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
 
-            let _crc = cursor.read_u32::<BigEndian>().with_context(|| "Could not read CRC")?;
+            if chunk_type == PLTE && position == ChunkPosition::BeforePlte {
+                position = ChunkPosition::BeforeIdat;
+            }
+            if chunk_type == IDAT {
+                position = ChunkPosition::AfterIdat;
+            }
 
             if chunk_type == IHDR{
-                if length != 13{
-                    bail!("Length doesn't match 13 chunk length");
-                }
-                let mut data_cursor = Cursor::new(data);
-                let width = data_cursor.read_u32::<BigEndian>().with_context(|| "Could not read width")?;
-                let height = data_cursor.read_u32::<BigEndian>().with_context(|| "Could not read height")?;
-                let bit_depth = data_cursor.read_u8().with_context(|| "Could not read bit_depth")?;
-                let color_type = data_cursor.read_u8().with_context(|| "Could not read color type")?;
-                let compression = data_cursor.read_u8().with_context(|| "Could not read compression")?;
-                let filter = data_cursor.read_u8().with_context(|| "Could not read filter type")?;
-                let interlace = data_cursor.read_u8().with_context(|| "Could not read interlace")?;
-
-                if compression != 0 && filter != 0 {
-                    bail!("Unsupported compression format for image data.");
-                }
-                if interlace != 0 {
-                    bail!("Interlaced PNG not supported in this minimal decoder");
-                }
-                if bit_depth != 8 {
-                    bail!("Only 8-bit PNG supported in this minimal decoder");
-                }
-                if color_type != 2 && color_type != 6 {
-                    bail!("Only color types 2 (RGB) and 6 (RGBA) supported");
+                info = Some(parse_ihdr(&data, limits)?);
+            }
+            else if chunk_type == IDAT && !idat_broken{
+                match decrypt_chunk(data, decryption_key) {
+                    Ok(decrypted_data) => idat_data.extend(&decrypted_data[..]),
+                    Err(_) if recover => {
+                        report.damage.push(Damage::UndecryptableChunk { chunk_type, offset });
+                        idat_broken = true;
+                    },
+                    Err(e) => return Err(e),
                 }
-                info = Some(PngInfo{
-                    width,
-                    height,
-                    bit_depth,
-                    color_type,
-                    interlace,
-                    image_type: parse_image_type(color_type, bit_depth)
-                })
-            }
-            else if chunk_type == IDAT{
-                let decrypted_data = if decryption_key.is_some() && data.len() > 12 {
-                    let key = decryption_key.unwrap();
-                    let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| anyhow::anyhow!(e))?;
-
-                    // Extract nonce (first 12 bytes) and ciphertext (rest)
-                    let nonce = Nonce::try_from(&data[..12]).map_err(|e| anyhow::anyhow!(e))?;
-                    let ciphertext = &data[12..];
-
-                    // Decrypt
-                    cipher.decrypt(&nonce, ciphertext).map_err(|e| anyhow::anyhow!(e))?
-                } else {
-                    data
-                };
-                idat_data.extend(&decrypted_data[..]);
+            }
+            else if chunk_type == PLTE{
+                palette = Some(parse_palette(&data)?);
+            }
+            else if chunk_type == TRNS{
+                transparency = Some(data);
             }
             else if chunk_type == IEND{
+                seen_iend = true;
                 break;
             }
-            else {
-                // ignore
+            else if chunk_type == GAMA && let Ok(value) = parse_gamma(&data) {
+                gamma = Some(value);
+            }
+            else if chunk_type == CHRM && let Ok(value) = parse_chromaticities(&data) {
+                chromaticities = Some(value);
+            }
+            else if chunk_type == SRGB && let Ok(value) = parse_srgb(&data) {
+                srgb = Some(value);
+            }
+            else if chunk_type == ICCP && let Ok(profile) = IccProfile::parse(&data, limits.max_decompressed_bytes) {
+                icc_profile = Some(profile);
+            }
+            else if chunk_type == CICP && let Ok(value) = parse_cicp(&data) {
+                cicp = Some(value);
+            }
+            else if chunk_type == MDCV && let Ok(value) = parse_mastering_display(&data) {
+                mastering_display = Some(value);
+            }
+            else if chunk_type == CLLI && let Ok(value) = parse_content_light_level(&data) {
+                content_light_level = Some(value);
+            }
+            else if chunk_type == PHYS && let Ok(value) = parse_physical(&data) {
+                physical = Some(value);
+            }
+            else if chunk_type == EXIF && let Ok(value) = Exif::parse(&data) {
+                exif = Some(value);
+            }
+            else if chunk_type == BKGD && let Some(header) = &info && let Ok(value) = parse_background(&data, header, palette.as_deref()) {
+                background = Some(value);
+            }
+            else if chunk_type == SBIT && let Some(header) = &info && let Ok(value) = parse_significant_bits(&data, header) {
+                significant_bits = Some(value);
+            }
+            else if is_text_chunk(&chunk_type) && let Ok(text_chunk) = TextChunk::parse(&chunk_type, &data, limits.max_decompressed_bytes) {
+                text.push(TextChunk { position, ..text_chunk });
+            }
+            else if chunk_type[0] & 0x20 != 0 {
+                // tRNS is folded into the pixels, everything else ancillary (including text we can't parse) is kept as is
+                chunks.push(AncillaryChunk { chunk_type, data, position });
             }
         }
 
-        let info = info.ok_or("Missing IHDR image info.").unwrap();
+        if !recover {
+            order.finish()?;
+        } else if !seen_iend {
+            report.damage.push(Damage::MissingIend);
+        }
 
-        let mut decoder = ZlibDecoder::new(&idat_data[..]);
-        let mut raw: Vec<u8> = Vec::new();
-        decoder.read_to_end(&mut raw).with_context(|| "Could not read file")?;
+        let mut info = info.ok_or_else(|| PngError::Malformed("Missing IHDR image info.".to_string()))?;
+        attach_palette(&mut info, palette, transparency)?;
+        info.gamma = gamma;
+        info.chromaticities = chromaticities;
+        info.srgb = srgb;
+        info.icc_profile = icc_profile;
+        info.physical = physical;
+        info.exif = exif;
+        info.background = background;
+        info.significant_bits = significant_bits;
+        info.cicp = cicp;
+        info.mastering_display = mastering_display;
+        info.content_light_level = content_light_level;
 
-        // Truecolor with alpha: red, green, blue, alpha.
-        // Truecolor: red, green, blue
+        let width = info.width as usize;
+        let height = info.height as usize;
+        let passes = image_passes(info.interlace, width, height);
+        let expected = image_data_size(&info, &passes, limits)?;
+
+        // Why recovery couldn't get every row out of the image data
+        let mut incomplete: Option<String> = None;
+        let raw = if recover {
+            let (raw, error) = inflate_partial(&idat_data, expected as u64);
+            match error {
+                Some(PngError::BadAdler32(mismatch)) => report.adler32_mismatch = Some(mismatch),
+                Some(e) => incomplete = Some(e.to_string()),
+                None if raw.len() < expected => incomplete = Some(format!("image data is {} bytes, expected {}", raw.len(), expected)),
+                None => {},
+            }
+            raw
+        } else {
+            // Anything past the expected size is an error anyway, so that's as far as we inflate
+            let (raw, adler32_mismatch) = inflate(&idat_data, expected as u64).map_err(|e| match e {
+                PngError::LimitExceeded(_) => PngError::LimitExceeded(format!("image data inflates to more than the {} bytes IHDR declares", expected)),
+                e => e,
+            })?;
+            if let Some(mismatch) = adler32_mismatch {
+                if options.crc_mode == CrcMode::Strict {
+                    return Err(PngError::BadAdler32(mismatch));
+                }
+                report.adler32_mismatch = Some(mismatch);
+            }
 
-        let bytes_per_pixel = match info.image_type {
-            ImageType::TruecolorAlpha => 4, // RGBA
-            ImageType::Truecolor => 3,      // RGB
-            _ => panic!("Unsupported color type")
+            if raw.len() != expected {
+                return Err(PngError::Malformed(format!("Decompressed image data is {} bytes, expected {}", raw.len(), expected)));
+            }
+            raw
         };
 
-        let width = info.width as usize;
-        let height = info.height as usize;
-        let row_bytes = width * bytes_per_pixel;
-        let expected = height * (1 +row_bytes); // 7.3 there is one filter byte per row
+        pb.inc(1);
 
-        if raw.len() != expected {
-            bail!("Decompressed image data doesn't match expected image data.");
+        pb.set_message("Unfilting rows in image...");
+        let DecodedPixels { rgba, rgba16, rows, total_rows } = decode_pixels(&info, &raw)?;
+        if let Some(reason) = incomplete {
+            report.damage.push(Damage::IncompleteImageData { rows, total_rows, reason });
         }
+        pb.inc(2);
 
-        pb.inc(1);
+        let image = DecodedPng{
+            info,
+            rgba,
+            rgba16,
+            report,
+            chunks,
+            text,
+            pixels_modified: false,
+        };
 
-        let mut unfiltered = vec![0u8; height * row_bytes];
+        Ok(image)
+    }
+}
+
+// Pixels decoded from inflated image data
+pub struct DecodedPixels {
+    pub rgba: Vec<u8>,
+    pub rgba16: Option<Vec<u16>>,
+    // Complete rows found, only fewer than `total_rows` when the data was cut short
+    pub rows: usize,
+    pub total_rows: usize,
+}
+
+// Unfilters every pass of `raw` and expands it to RGBA. Passes the data runs out in keep their complete
+// rows, whatever couldn't be reconstructed stays transparent black.
+pub fn decode_pixels(info: &PngInfo, raw: &[u8]) -> Result<DecodedPixels> {
+    let width = info.width as usize;
+    let height = info.height as usize;
+    let passes = image_passes(info.interlace, width, height);
+
+    let mut offset = 0;
+    let mut unfiltered_passes = Vec::with_capacity(passes.len());
+    let (mut rows, mut total_rows) = (0, 0);
+    for pass in passes.iter().filter(|pass| !pass.is_empty()) {
+        let row_length = 1 + info.row_bytes(pass.width);
+        let pass_rows = pass.height.min((raw.len() - offset) / row_length);
+        total_rows += pass.height;
+        let pass = Pass { height: pass_rows, ..*pass };
+        let length = pass_rows * row_length;
+        unfiltered_passes.push((pass, unfilter_pass(info, &pass, &raw[offset..offset + length])?));
+        offset += length;
+        rows += pass_rows;
+    }
 
-        pb.set_message("Unfilting rows in image...");
-        for row in 0..height {
-            let start = row * (1 +row_bytes);
-            let filter_type = raw[start];
-            let source = &raw[start + 1 .. start + 1 + row_bytes];
-
-            let dest_row_start = row * row_bytes;
-
-            let prev = if row == 0 {
-                None
-            } else {
-                let prev_start = (row - 1) * row_bytes;
-                let prev_data = unfiltered[prev_start..prev_start + row_bytes].to_vec();
-                Some(prev_data)
-            };
+    let mut rgba = Vec::new();
+    let mut rgba16 = None;
+    for (pass, unfiltered) in unfiltered_passes {
+        let pass_info = PngInfo {
+            width: pass.width as u32,
+            height: pass.height as u32,
+            ..info.clone()
+        };
+        let (pass_rgba, pass_rgba16) = if info.bit_depth == 16 {
+            let pass_rgba16 = expand_to_rgba16(&pass_info, &unfiltered);
+            (pass_rgba16.iter().map(|&sample| (sample >> 8) as u8).collect(), Some(pass_rgba16))
+        } else {
+            (expand_to_rgba(&pass_info, &unfiltered)?, None)
+        };
 
-            let dest = &mut unfiltered[dest_row_start..dest_row_start + row_bytes];
+        if info.interlace == 0 {
+            rgba = pass_rgba;
+            rgba16 = pass_rgba16;
+            continue;
+        }
 
-            unfilter_row(filter_type, bytes_per_pixel, source, prev, dest);
+        // Scatter the reduced image into its positions in the full image
+        rgba.resize(width * height * 4, 0);
+        scatter_pass(&pass, width, &pass_rgba, &mut rgba);
+        if let Some(pass_rgba16) = pass_rgba16 {
+            let full = rgba16.get_or_insert_with(|| vec![0u16; width * height * 4]);
+            scatter_pass(&pass, width, &pass_rgba16, full);
         }
-        pb.inc(1);
+    }
+    rgba.resize(width * height * 4, 0);
+    if let Some(rgba16) = rgba16.as_mut() {
+        rgba16.resize(width * height * 4, 0);
+    }
 
-        //let mut pixels: Vec<Pixel> = Vec::with_capacity(width * height);
+    Ok(DecodedPixels { rgba, rgba16, rows, total_rows })
+}
 
-        pb.set_message("Decompressed image data...");
-        let mut rgba = Vec::with_capacity(width * height * 4);
-        match info.image_type {
-            ImageType::Truecolor => {
-                for i in 0..(width * height) {
-                    let r = unfiltered[i * 3];
-                    let g = unfiltered[i * 3 + 1];
-                    let b = unfiltered[i * 3 + 2];
-                    rgba.extend_from_slice(&[r, g, b, 255]);
+fn unfilter_pass(info: &PngInfo, pass: &Pass, raw: &[u8]) -> Result<Vec<u8>> {
+    let bytes_per_pixel = info.filter_bytes_per_pixel();
+    let row_bytes = info.row_bytes(pass.width);
+    let mut unfiltered = vec![0u8; pass.height * row_bytes];
+
+    for row in 0..pass.height {
+        let start = row * (1 +row_bytes);
+        let filter_type = raw[start];
+        let source = &raw[start + 1 .. start + 1 + row_bytes];
+
+        let dest_row_start = row * row_bytes;
+        let (before, rest) = unfiltered.split_at_mut(dest_row_start);
+
+        let prev = if row == 0 {
+            None
+        } else {
+            Some(&before[dest_row_start - row_bytes..])
+        };
+
+        let dest = &mut rest[..row_bytes];
+
+        unfilter_row(filter_type, bytes_per_pixel, source, prev, dest)?;
+    }
+
+    Ok(unfiltered)
+}
+
+fn scatter_pass<T: Copy>(pass: &Pass, image_width: usize, pass_rgba: &[T], rgba: &mut [T]) {
+    for py in 0..pass.height {
+        for px in 0..pass.width {
+            let src = (py * pass.width + px) * 4;
+            let dst = pass.image_index(px, py, image_width) * 4;
+            rgba[dst..dst + 4].copy_from_slice(&pass_rgba[src..src + 4]);
+        }
+    }
+}
+
+pub fn parse_ihdr(data: &[u8], limits: &Limits) -> Result<PngInfo> {
+    if data.len() != 13 {
+        return Err(PngError::Malformed(format!("IHDR length is {}, expected 13", data.len())));
+    }
+    // Length is checked above so these reads can't run out of data
+    let width = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+    let height = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    let [bit_depth, color_type, compression, filter, interlace] = [data[8], data[9], data[10], data[11], data[12]];
+
+    // 11.2.1 dimensions are PNG four byte unsigned integers, limited to 2^31 - 1
+    if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+        return Err(PngError::Malformed(format!("Invalid image dimensions {}x{}", width, height)));
+    }
+    if compression != 0 || filter != 0 {
+        return Err(PngError::Unsupported(format!("compression method {} / filter method {}", compression, filter)));
+    }
+    if interlace > 1 {
+        return Err(PngError::Unsupported(format!("interlace method {}", interlace)));
+    }
+    check_dimensions(width, height, limits)?;
+
+    let image_type = parse_image_type(color_type, bit_depth);
+    if image_type == ImageType::Unknown {
+        return Err(PngError::Malformed(format!("Invalid bit depth {} for color type {}", bit_depth, color_type)));
+    }
+
+    Ok(PngInfo{
+        width,
+        height,
+        bit_depth,
+        color_type,
+        interlace,
+        image_type,
+        ..Default::default()
+    })
+}
+
+pub fn parse_palette(data: &[u8]) -> Result<Vec<[u8; 3]>> {
+    if data.is_empty() || !data.len().is_multiple_of(3) || data.len() / 3 > 256 {
+        return Err(PngError::Malformed(format!("PLTE chunk length {} is not a multiple of 3 between 3 and 768", data.len())));
+    }
+    Ok(data.chunks_exact(3).map(|rgb| [rgb[0], rgb[1], rgb[2]]).collect())
+}
+
+// https://www.w3.org/TR/png-3/#11pHYs
+pub fn parse_physical(data: &[u8]) -> Result<PhysicalDimensions> {
+    if data.len() != 9 {
+        return Err(PngError::Malformed(format!("pHYs length is {}, expected 9", data.len())));
+    }
+    let unit = match data[8] {
+        0 => PhysicalUnit::Unknown,
+        1 => PhysicalUnit::Meter,
+        unit => return Err(PngError::Malformed(format!("pHYs unit {} is invalid", unit))),
+    };
+    Ok(PhysicalDimensions {
+        x: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+        y: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+        unit,
+    })
+}
+
+// https://www.w3.org/TR/png-3/#11sBIT
+pub fn parse_significant_bits(data: &[u8], info: &PngInfo) -> Result<SignificantBits> {
+    let depth = if info.image_type == ImageType::IndexedColor { 8 } else { info.bit_depth };
+    if let Some(&bits) = data.iter().find(|&&bits| bits == 0 || bits > depth) {
+        return Err(PngError::Malformed(format!("sBIT value {} is out of range for sample depth {}", bits, depth)));
+    }
+    match (info.image_type, data) {
+        (ImageType::Grayscale, &[gray]) => Ok(SignificantBits { rgb: [gray; 3], alpha: None }),
+        (ImageType::Truecolor | ImageType::IndexedColor, &[red, green, blue]) => Ok(SignificantBits { rgb: [red, green, blue], alpha: None }),
+        (ImageType::GrayscaleAlpha, &[gray, alpha]) => Ok(SignificantBits { rgb: [gray; 3], alpha: Some(alpha) }),
+        (ImageType::TruecolorAlpha, &[red, green, blue, alpha]) => Ok(SignificantBits { rgb: [red, green, blue], alpha: Some(alpha) }),
+        _ => Err(PngError::Malformed(format!("sBIT length {} is invalid for color type {}", data.len(), info.color_type))),
+    }
+}
+
+// Chunks written with an encryption key are the 12 byte nonce followed by the AES-GCM ciphertext
+pub fn decrypt_chunk(data: Vec<u8>, decryption_key: Option<&[u8; 32]>) -> Result<Vec<u8>> {
+    let Some(key) = decryption_key else {
+        return Ok(data);
+    };
+    if data.len() <= 12 {
+        return Ok(data);
+    }
+
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| PngError::Decryption)?;
+
+    // Extract nonce (first 12 bytes) and ciphertext (rest)
+    let nonce = Nonce::try_from(&data[..12]).map_err(|_| PngError::Decryption)?;
+    let ciphertext = &data[12..];
+
+    // Decrypt
+    cipher.decrypt(&nonce, ciphertext).map_err(|_| PngError::Decryption)
+}
+
+// Undoes write_ancillary_chunk. Critical chunks come back as they are.
+pub fn decrypt_ancillary_chunk(chunk_type: &[u8; 4], data: Vec<u8>, decryption_key: Option<&[u8; 32]>) -> Result<Vec<u8>> {
+    let Some(key) = decryption_key else {
+        return Ok(data);
+    };
+    if chunk_type[0] & 0x20 == 0 {
+        return Ok(data);
+    }
+    if SEQUENCED_CHUNKS.contains(chunk_type) {
+        return decrypt_sequenced_chunk(chunk_type, &data, key);
+    }
+    if data.len() <= 12 {
+        return Err(PngError::Decryption);
+    }
+
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| PngError::Decryption)?;
+    let nonce = Nonce::try_from(&data[..12]).map_err(|_| PngError::Decryption)?;
+    cipher
+        .decrypt(&nonce, Payload { msg: &data[12..], aad: chunk_type })
+        .map_err(|_| PngError::Decryption)
+}
+
+// Encrypted fcTL and fdAT data is the plain sequence number followed by the encrypted rest, which has the chunk
+// type and sequence number as associated data
+fn decrypt_sequenced_chunk(chunk_type: &[u8; 4], data: &[u8], decryption_key: &[u8; 32]) -> Result<Vec<u8>> {
+    let Some((sequence, encrypted)) = data.split_first_chunk::<4>() else {
+        return Err(PngError::Malformed(format!("{} is missing its sequence number", String::from_utf8_lossy(chunk_type))));
+    };
+    if encrypted.len() <= 12 {
+        return Err(PngError::Decryption);
+    }
+
+    let cipher = Aes256Gcm::new_from_slice(decryption_key).map_err(|_| PngError::Decryption)?;
+    let nonce = Nonce::try_from(&encrypted[..12]).map_err(|_| PngError::Decryption)?;
+    let rest = cipher
+        .decrypt(&nonce, Payload { msg: &encrypted[12..], aad: &[chunk_type.as_slice(), sequence].concat() })
+        .map_err(|_| PngError::Decryption)?;
+    Ok([sequence.as_slice(), &rest].concat())
+}
+
+pub fn check_chunk_limits(length: u32, chunk_count: u32, limits: &Limits) -> Result<()> {
+    if chunk_count > limits.max_chunks {
+        return Err(PngError::LimitExceeded(format!("more than {} chunks", limits.max_chunks)));
+    }
+    if length > limits.max_chunk_size {
+        return Err(PngError::LimitExceeded(format!("{} byte chunk is larger than {} bytes", length, limits.max_chunk_size)));
+    }
+    Ok(())
+}
+
+// `mismatch` holds both CRCs, it only counts as a mismatch when they differ
+pub fn verify_crc(mismatch: CrcMismatch, crc_mode: CrcMode, report: &mut DecodeReport) -> Result<()> {
+    if mismatch.stored == mismatch.computed {
+        return Ok(());
+    }
+    if crc_mode == CrcMode::Strict {
+        return Err(PngError::BadCrc(mismatch));
+    }
+    report.crc_mismatches.push(mismatch);
+    Ok(())
+}
+
+// Size of the inflated image data. 7.3 there is one filter byte per row, empty passes have no rows at all
+pub fn image_data_size(info: &PngInfo, passes: &[Pass], limits: &Limits) -> Result<usize> {
+    let expected = passes.iter()
+        .filter(|pass| !pass.is_empty())
+        .try_fold(0usize, |total, pass| pass.height.checked_mul(1 + info.row_bytes(pass.width))?.checked_add(total))
+        .ok_or_else(|| PngError::LimitExceeded(format!("{}x{} image is too large to address", info.width, info.height)))?;
+    if expected as u64 > limits.max_decompressed_bytes {
+        return Err(PngError::LimitExceeded(format!("image data inflates to {} bytes, more than {}", expected, limits.max_decompressed_bytes)));
+    }
+    Ok(expected)
+}
+
+fn check_dimensions(width: u32, height: u32, limits: &Limits) -> Result<()> {
+    if width > limits.max_width || height > limits.max_height {
+        return Err(PngError::LimitExceeded(format!("{}x{} image is larger than {}x{}", width, height, limits.max_width, limits.max_height)));
+    }
+    if width as u64 * height as u64 > limits.max_pixels {
+        return Err(PngError::LimitExceeded(format!("{}x{} image has more than {} pixels", width, height, limits.max_pixels)));
+    }
+    Ok(())
+}
+
+fn truncated(what: &str) -> PngError {
+    PngError::Truncated(what.to_string())
+}
+
+// Reads the sample at index `x` of an unfiltered scanline. Samples below 8 bits are packed
+// leftmost pixel in the high-order bits, 16-bit samples are big endian https://www.w3.org/TR/png-3/#7Integers-and-byte-order
+pub fn read_sample(row: &[u8], x: usize, bit_depth: u8) -> u16 {
+    match bit_depth {
+        16 => u16::from_be_bytes([row[x * 2], row[x * 2 + 1]]),
+        8 => row[x] as u16,
+        _ => {
+            let bit_offset = x * bit_depth as usize;
+            let byte = row[bit_offset / 8];
+            let shift = 8 - bit_depth as usize - (bit_offset % 8);
+            let mask = (1u16 << bit_depth) - 1;
+            (byte as u16 >> shift) & mask
+        }
+    }
+}
+
+// Maps a sample onto the 0..=255 range, low bit depths are replicated up so that the
+// maximum sample is always 255 https://www.w3.org/TR/png-3/#13Sample-depth-rescaling
+pub fn scale_to_u8(sample: u16, bit_depth: u8) -> u8 {
+    match bit_depth {
+        16 => (sample >> 8) as u8,
+        8 => sample as u8,
+        _ => {
+            let max = (1u32 << bit_depth) - 1;
+            ((sample as u32 * 255 + max / 2) / max) as u8
+        }
+    }
+}
+
+// Tracks where we are in the chunk stream and enforces the ordering rules https://www.w3.org/TR/png-3/#5ChunkOrdering
+// Only the critical chunk rules are errors. Ancillary chunks in the wrong place are common in real files and
+// viewers show those files anyway, `check` returns false for them so the caller can ignore the chunk.
+#[derive(Default)]
+pub struct ChunkOrder {
+    seen_ihdr: bool,
+    seen_plte: bool,
+    seen_idat: bool,
+    idat_finished: bool,
+    seen_iend: bool,
+}
+
+impl ChunkOrder {
+    pub fn check(&mut self, chunk_type: &[u8; 4], info: Option<&PngInfo>) -> Result<bool> {
+        let name = String::from_utf8_lossy(chunk_type);
+
+        if !self.seen_ihdr && *chunk_type != IHDR {
+            return Err(PngError::Malformed(format!("IHDR must be the first chunk, found {}", name)));
+        }
+        if self.seen_idat && *chunk_type != IDAT {
+            self.idat_finished = true;
+        }
+
+        match *chunk_type {
+            IHDR => {
+                if self.seen_ihdr {
+                    return Err(PngError::Malformed("Multiple IHDR chunks".to_string()));
+                }
+                self.seen_ihdr = true;
+            },
+            PLTE => {
+                if self.seen_plte {
+                    return Err(PngError::Malformed("Multiple PLTE chunks".to_string()));
+                }
+                if self.seen_idat {
+                    return Err(PngError::Malformed("PLTE chunk must come before the first IDAT".to_string()));
+                }
+                self.seen_plte = true;
+            },
+            IDAT => {
+                if self.idat_finished {
+                    return Err(PngError::Malformed("IDAT chunks must be consecutive".to_string()));
+                }
+                let indexed = info.is_some_and(|info| info.image_type == ImageType::IndexedColor);
+                if indexed && !self.seen_plte {
+                    return Err(PngError::Malformed("PLTE chunk must come before the first IDAT".to_string()));
                 }
+                self.seen_idat = true;
             },
-            ImageType::TruecolorAlpha => {
-                rgba = unfiltered;
+            IEND => {
+                self.seen_iend = true;
             },
-            _ => unreachable!()
+            // Before PLTE and IDAT
+            CHRM | GAMA | ICCP | SBIT | SRGB | CICP | MDCV => return Ok(!self.seen_plte && !self.seen_idat),
+            // After PLTE for indexed images, before IDAT
+            TRNS | BKGD | HIST => {
+                let indexed = info.is_some_and(|info| info.image_type == ImageType::IndexedColor);
+                return Ok((self.seen_plte || !indexed) && !self.seen_idat);
+            },
+            CLLI | PHYS | SPLT | EXIF | ACTL => return Ok(!self.seen_idat),
+            _ => {
+                // 5.4 bit 5 of the first byte clear means the chunk is critical, and we can't skip what we don't understand
+                if chunk_type[0] & 0x20 == 0 {
+                    return Err(PngError::Unsupported(format!("unknown critical chunk {}", name)));
+                }
+            }
         }
-        pb.inc(1);
 
-        let image = DecodedPng{
-            info,
-            rgba,
-        };
+        Ok(true)
+    }
 
-        Ok(image)
+    pub fn finish(&self) -> Result<()> {
+        if !self.seen_ihdr {
+            return Err(PngError::Malformed("Missing IHDR chunk".to_string()));
+        }
+        if !self.seen_idat {
+            return Err(PngError::Malformed("Missing IDAT chunk".to_string()));
+        }
+        if !self.seen_iend {
+            return Err(PngError::Malformed("Missing IEND chunk".to_string()));
+        }
+        Ok(())
+    }
+}
+
+// Validates PLTE/tRNS against the image header https://www.w3.org/TR/png-3/#11PLTE
+pub fn attach_palette(info: &mut PngInfo, palette: Option<Vec<[u8; 3]>>, transparency: Option<Vec<u8>>) -> Result<()> {
+    match (&info.image_type, &palette) {
+        (ImageType::IndexedColor, None) => return Err(PngError::Malformed("Indexed color image is missing its PLTE chunk".to_string())),
+        (ImageType::IndexedColor, Some(entries)) if entries.len() > 1 << info.bit_depth => {
+            return Err(PngError::Malformed(format!("PLTE has {} entries, more than bit depth {} can index", entries.len(), info.bit_depth)))
+        },
+        (ImageType::Grayscale | ImageType::GrayscaleAlpha, Some(_)) => return Err(PngError::Malformed("PLTE chunk is not allowed in grayscale images".to_string())),
+        _ => {}
+    }
+
+    info.transparency = match transparency {
+        None => None,
+        Some(data) => Some(match info.image_type {
+            ImageType::Grayscale if data.len() == 2 => {
+                Transparency::Gray(u16::from_be_bytes([data[0], data[1]]))
+            },
+            ImageType::Truecolor if data.len() == 6 => Transparency::Rgb(
+                u16::from_be_bytes([data[0], data[1]]),
+                u16::from_be_bytes([data[2], data[3]]),
+                u16::from_be_bytes([data[4], data[5]]),
+            ),
+            ImageType::IndexedColor if data.len() <= palette.as_ref().map_or(0, |p| p.len()) => {
+                Transparency::Palette(data)
+            },
+            _ => return Err(PngError::Malformed(format!("tRNS chunk of length {} is invalid for color type {}", data.len(), info.color_type))),
+        }),
+    };
+    info.palette = palette;
+
+    Ok(())
+}
+
+pub fn expand_to_rgba(info: &PngInfo, unfiltered: &[u8]) -> Result<Vec<u8>> {
+    let width = info.width as usize;
+    let height = info.height as usize;
+    let row_bytes = info.row_bytes(width);
+    let mut rgba = Vec::with_capacity(width * height * 4);
+
+    match info.image_type {
+        ImageType::Grayscale => {
+            let key = match info.transparency {
+                Some(Transparency::Gray(key)) => Some(key),
+                _ => None,
+            };
+            for row in unfiltered.chunks_exact(row_bytes) {
+                for x in 0..width {
+                    let sample = read_sample(row, x, info.bit_depth);
+                    let v = scale_to_u8(sample, info.bit_depth);
+                    let alpha = if key == Some(sample) { 0 } else { 255 };
+                    rgba.extend_from_slice(&[v, v, v, alpha]);
+                }
+            }
+        },
+        ImageType::Truecolor => {
+            let key = match info.transparency {
+                Some(Transparency::Rgb(r, g, b)) => Some([r, g, b]),
+                _ => None,
+            };
+            for pixel in unfiltered.chunks_exact(3) {
+                let rgb = [pixel[0] as u16, pixel[1] as u16, pixel[2] as u16];
+                let alpha = if key == Some(rgb) { 0 } else { 255 };
+                rgba.extend_from_slice(&[pixel[0], pixel[1], pixel[2], alpha]);
+            }
+        },
+        ImageType::IndexedColor => {
+            let palette = info.palette.as_deref().unwrap_or(&[]);
+            let alphas: &[u8] = match &info.transparency {
+                Some(Transparency::Palette(alphas)) => alphas,
+                _ => &[],
+            };
+            for row in unfiltered.chunks_exact(row_bytes) {
+                for x in 0..width {
+                    let index = read_sample(row, x, info.bit_depth) as usize;
+                    let Some(&[r, g, b]) = palette.get(index) else {
+                        return Err(PngError::Malformed(format!("Palette index {} out of range for {} entry PLTE", index, palette.len())));
+                    };
+                    let alpha = alphas.get(index).copied().unwrap_or(255);
+                    rgba.extend_from_slice(&[r, g, b, alpha]);
+                }
+            }
+        },
+        ImageType::GrayscaleAlpha => {
+            for row in unfiltered.chunks_exact(row_bytes) {
+                for x in 0..width {
+                    let v = scale_to_u8(read_sample(row, x * 2, info.bit_depth), info.bit_depth);
+                    let alpha = scale_to_u8(read_sample(row, x * 2 + 1, info.bit_depth), info.bit_depth);
+                    rgba.extend_from_slice(&[v, v, v, alpha]);
+                }
+            }
+        },
+        ImageType::TruecolorAlpha => {
+            rgba.extend_from_slice(unfiltered);
+        },
+        ImageType::Unknown => return Err(PngError::Unsupported(format!("color type {}", info.color_type))),
+    }
+
+    Ok(rgba)
+}
+
+// 16-bit samples have no padding so every pixel is exactly channels * 2 bytes
+pub fn expand_to_rgba16(info: &PngInfo, unfiltered: &[u8]) -> Vec<u16> {
+    let width = info.width as usize;
+    let height = info.height as usize;
+    let channels = info.image_type.channels();
+    let mut rgba16 = Vec::with_capacity(width * height * 4);
+
+    for pixel in unfiltered.chunks_exact(channels * 2) {
+        let sample = |i: usize| u16::from_be_bytes([pixel[i * 2], pixel[i * 2 + 1]]);
+        let keyed_alpha = |matches: bool| if matches { 0 } else { u16::MAX };
+
+        match info.image_type {
+            ImageType::Grayscale => {
+                let gray = sample(0);
+                let alpha = keyed_alpha(info.transparency == Some(Transparency::Gray(gray)));
+                rgba16.extend_from_slice(&[gray, gray, gray, alpha]);
+            },
+            ImageType::GrayscaleAlpha => {
+                rgba16.extend_from_slice(&[sample(0), sample(0), sample(0), sample(1)]);
+            },
+            ImageType::Truecolor => {
+                let (r, g, b) = (sample(0), sample(1), sample(2));
+                let alpha = keyed_alpha(info.transparency == Some(Transparency::Rgb(r, g, b)));
+                rgba16.extend_from_slice(&[r, g, b, alpha]);
+            },
+            _ => rgba16.extend_from_slice(&[sample(0), sample(1), sample(2), sample(3)]),
+        }
     }
-}
\ No newline at end of file
+
+    rgba16
+}
@@ -0,0 +1,720 @@
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use crc32fast::Hasher;
+use indicatif::ProgressBar;
+use flate2::{Compression, Decompress, FlushDecompress, Status};
+use flate2::write::ZlibEncoder;
+use crate::png::constants::*;
+use crate::png::error::{PngError, Result};
+use crate::png::color::{parse_chromaticities, parse_gamma, parse_srgb};
+use crate::png::filter::unfilter_row;
+use crate::png::background::parse_background;
+use crate::png::exif::Exif;
+use crate::png::hdr::{parse_cicp, parse_content_light_level, parse_mastering_display};
+use crate::png::icc::IccProfile;
+use crate::png::text::{is_text_chunk, TextChunk};
+use crate::png::interlace::{image_passes, Pass};
+use crate::png::optimization::choose_best_filter;
+use crate::png::parse_image_type;
+use crate::png::read::*;
+use crate::png::types::*;
+use crate::png::write::{write_chunk, zopfli_options};
+use crate::png::zlib::{check_header, Adler32, Adler32Mismatch};
+
+// Compressed data is pulled out of IDAT chunks this many bytes at a time
+const INPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+// One unfiltered scanline, still in the PNG sample layout. Interlaced images arrive pass by pass,
+// row `y` of `pass` holds image pixels (pass.x + px * pass.dx, pass.y + y * pass.dy).
+pub struct Scanline<'a> {
+    pub pass: Pass,
+    pub y: usize,
+    pub data: &'a [u8],
+}
+
+impl Scanline<'_> {
+    #[allow(dead_code)]
+    pub fn image_y(&self) -> usize {
+        self.pass.y + self.y * self.pass.dy
+    }
+
+    // Same layout as `DecodedPng::rgba`, 16-bit samples keep their high byte
+    #[allow(dead_code)]
+    pub fn to_rgba(&self, info: &PngInfo) -> Result<Vec<u8>> {
+        let row_info = PngInfo {
+            width: self.pass.width as u32,
+            height: 1,
+            ..info.clone()
+        };
+        if info.bit_depth == 16 {
+            Ok(expand_to_rgba16(&row_info, self.data).iter().map(|&sample| (sample >> 8) as u8).collect())
+        } else {
+            expand_to_rgba(&row_info, self.data)
+        }
+    }
+}
+
+struct ChunkHeader {
+    offset: usize,
+    length: usize,
+    chunk_type: [u8; 4],
+    // An ancillary chunk out of order, its data is read and ignored
+    misplaced: bool,
+}
+
+// Decodes a PNG from any reader one scanline at a time. Compressed data is read and inflated as rows
+// are requested, so memory use is the input buffer plus the current and previous scanline no matter
+// how big the image is. Encrypted IDAT chunks have to be read whole before they can be authenticated.
+pub struct StreamingDecoder<R: Read> {
+    reader: R,
+    position: usize,
+    decryption_key: Option<[u8; 32]>,
+    options: DecodeOptions,
+    info: PngInfo,
+    order: ChunkOrder,
+    report: DecodeReport,
+    chunk_count: u32,
+    // Ancillary chunks and text kept like the whole file decoder keeps them, the ones after the image data
+    // arrive once every row has been read
+    chunks: Vec<AncillaryChunk>,
+    text: Vec<TextChunk>,
+
+    // The IDAT chunk being read: its header, bytes left and running CRC
+    idat: Option<(ChunkHeader, usize, Hasher)>,
+    // First chunk after the IDAT run, found while looking for more image data
+    pending: Option<ChunkHeader>,
+    input: Vec<u8>,
+    input_pos: usize,
+    zlib_header: Vec<u8>,
+    inflater: Decompress,
+    adler: Adler32,
+    stream_ended: bool,
+
+    passes: Vec<Pass>,
+    pass_index: usize,
+    row_in_pass: usize,
+    filtered: Vec<u8>,
+    prev: Vec<u8>,
+    current: Vec<u8>,
+    finished: bool,
+}
+
+impl<R: Read> StreamingDecoder<R> {
+    // Reads everything up to the first IDAT chunk
+    pub fn new(reader: R, decryption_key: Option<&[u8; 32]>, options: DecodeOptions) -> Result<Self> {
+        let mut decoder = StreamingDecoder {
+            reader,
+            position: 0,
+            decryption_key: decryption_key.copied(),
+            options,
+            info: PngInfo::default(),
+            order: ChunkOrder::default(),
+            report: DecodeReport::default(),
+            chunk_count: 0,
+            chunks: Vec::new(),
+            text: Vec::new(),
+            idat: None,
+            pending: None,
+            input: Vec::new(),
+            input_pos: 0,
+            zlib_header: Vec::with_capacity(2),
+            // The zlib header and trailer are handled here so the Adler-32 can be checked leniently
+            inflater: Decompress::new(false),
+            adler: Adler32::default(),
+            stream_ended: false,
+            passes: Vec::new(),
+            pass_index: 0,
+            row_in_pass: 0,
+            filtered: Vec::new(),
+            prev: Vec::new(),
+            current: Vec::new(),
+            finished: false,
+        };
+
+        let mut signature = [0u8; 8];
+        decoder.read_exact(&mut signature, "signature").map_err(|_| PngError::BadSignature)?;
+        if signature != PNG_SIG {
+            return Err(PngError::BadSignature);
+        }
+
+        let mut palette: Option<Vec<[u8; 3]>> = None;
+        let mut transparency: Option<Vec<u8>> = None;
+        loop {
+            let header = decoder.read_chunk_header()?.ok_or_else(|| truncated("image data"))?;
+            if header.chunk_type == IDAT {
+                decoder.start_idat(header)?;
+                break;
+            }
+
+            let data = decoder.read_chunk_data(&header)?;
+            if header.misplaced {
+                continue;
+            }
+            let data = decrypt_ancillary_chunk(&header.chunk_type, data, decoder.decryption_key.as_ref())?;
+            match header.chunk_type {
+                IHDR => decoder.info = parse_ihdr(&data, &decoder.options.limits)?,
+                PLTE => palette = Some(parse_palette(&data)?),
+                TRNS => transparency = Some(data),
+                IEND => return Err(PngError::Malformed("Missing IDAT chunk".to_string())),
+                chunk_type => {
+                    let position = if palette.is_some() { ChunkPosition::BeforeIdat } else { ChunkPosition::BeforePlte };
+                    decoder.add_ancillary(chunk_type, data, position, palette.as_deref());
+                },
+            }
+        }
+
+        attach_palette(&mut decoder.info, palette, transparency)?;
+
+        decoder.passes = image_passes(decoder.info.interlace, decoder.info.width as usize, decoder.info.height as usize);
+        image_data_size(&decoder.info, &decoder.passes, &decoder.options.limits)?;
+
+        let max_row_bytes = decoder.passes.iter().map(|pass| decoder.info.row_bytes(pass.width)).max().unwrap_or(0);
+        decoder.filtered = vec![0u8; 1 + max_row_bytes];
+        decoder.prev = vec![0u8; max_row_bytes];
+        decoder.current = vec![0u8; max_row_bytes];
+
+        Ok(decoder)
+    }
+
+    #[allow(dead_code)]
+    pub fn info(&self) -> &PngInfo {
+        &self.info
+    }
+
+    #[allow(dead_code)]
+    pub fn report(&self) -> &DecodeReport {
+        &self.report
+    }
+
+    // Returns the next scanline, or None once every row has been read and the rest of the file checked
+    pub fn next_row(&mut self) -> Result<Option<Scanline<'_>>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        while self.pass_index < self.passes.len() && self.passes[self.pass_index].is_empty() {
+            self.pass_index += 1;
+        }
+        if self.pass_index == self.passes.len() {
+            self.finish()?;
+            self.finished = true;
+            return Ok(None);
+        }
+
+        let pass = self.passes[self.pass_index];
+        let row_bytes = self.info.row_bytes(pass.width);
+        self.inflate_into(1 + row_bytes)?;
+
+        // The row we just produced becomes the one the next row predicts from
+        if self.row_in_pass > 0 {
+            std::mem::swap(&mut self.prev, &mut self.current);
+        }
+        let prev = (self.row_in_pass > 0).then(|| &self.prev[..row_bytes]);
+        unfilter_row(self.filtered[0], self.info.filter_bytes_per_pixel(), &self.filtered[1..1 + row_bytes], prev, &mut self.current[..row_bytes])?;
+
+        let y = self.row_in_pass;
+        self.row_in_pass += 1;
+        if self.row_in_pass == pass.height {
+            self.pass_index += 1;
+            self.row_in_pass = 0;
+        }
+
+        Ok(Some(Scanline { pass, y, data: &self.current[..row_bytes] }))
+    }
+
+    // Everything but the pixels, as a DecodedPng with empty `rgba`. Chunks after the image data are only
+    // there once `next_row` has returned None.
+    pub fn metadata(&self) -> DecodedPng {
+        DecodedPng {
+            info: self.info.clone(),
+            report: self.report.clone(),
+            chunks: self.chunks.clone(),
+            text: self.text.clone(),
+            ..Default::default()
+        }
+    }
+
+    // Reads every row into the same DecodedPng the whole file decoder returns. Only the finished pixels are
+    // held, not the file or its inflated image data.
+    pub fn decode(mut self, pb: &ProgressBar) -> Result<DecodedPng> {
+        let (width, height) = (self.info.width as usize, self.info.height as usize);
+        let mut rgba = vec![0u8; width * height * 4];
+        let mut rgba16 = (self.info.bit_depth == 16).then(|| vec![0u16; width * height * 4]);
+        // What expanding a row looks at, without the metadata that would be copied along for every row
+        let row_info = PngInfo {
+            height: 1,
+            bit_depth: self.info.bit_depth,
+            color_type: self.info.color_type,
+            image_type: self.info.image_type,
+            palette: self.info.palette.clone(),
+            transparency: self.info.transparency.clone(),
+            ..Default::default()
+        };
+        pb.inc(1);
+
+        pb.set_message("Unfilting rows in image...");
+        while let Some(row) = self.next_row()? {
+            let row_info = PngInfo { width: row.pass.width as u32, ..row_info.clone() };
+            let (row_rgba, row_rgba16) = match rgba16 {
+                Some(_) => {
+                    let row_rgba16 = expand_to_rgba16(&row_info, row.data);
+                    (row_rgba16.iter().map(|&sample| (sample >> 8) as u8).collect(), Some(row_rgba16))
+                },
+                None => (expand_to_rgba(&row_info, row.data)?, None),
+            };
+            for px in 0..row.pass.width {
+                let index = row.pass.image_index(px, row.y, width) * 4;
+                rgba[index..index + 4].copy_from_slice(&row_rgba[px * 4..px * 4 + 4]);
+                if let (Some(rgba16), Some(row_rgba16)) = (rgba16.as_mut(), &row_rgba16) {
+                    rgba16[index..index + 4].copy_from_slice(&row_rgba16[px * 4..px * 4 + 4]);
+                }
+            }
+        }
+        pb.inc(2);
+
+        Ok(DecodedPng { rgba, rgba16, ..self.metadata() })
+    }
+
+    #[allow(dead_code)]
+    pub fn for_each_row(mut self, mut f: impl FnMut(&PngInfo, Scanline) -> Result<()>) -> Result<DecodeReport> {
+        let info = self.info.clone();
+        while let Some(row) = self.next_row()? {
+            f(&info, row)?;
+        }
+        Ok(self.report)
+    }
+
+    // Chunks that don't become an `info` field, or that fail to parse, are kept as they are
+    fn add_ancillary(&mut self, chunk_type: [u8; 4], data: Vec<u8>, position: ChunkPosition, palette: Option<&[[u8; 3]]>) {
+        let info = &mut self.info;
+        let max_text_bytes = self.options.limits.max_decompressed_bytes;
+        let parsed = match chunk_type {
+            GAMA => parse_gamma(&data).map(|value| info.gamma = Some(value)).is_ok(),
+            CHRM => parse_chromaticities(&data).map(|value| info.chromaticities = Some(value)).is_ok(),
+            SRGB => parse_srgb(&data).map(|value| info.srgb = Some(value)).is_ok(),
+            ICCP => IccProfile::parse(&data, max_text_bytes).map(|profile| info.icc_profile = Some(profile)).is_ok(),
+            CICP => parse_cicp(&data).map(|value| info.cicp = Some(value)).is_ok(),
+            MDCV => parse_mastering_display(&data).map(|value| info.mastering_display = Some(value)).is_ok(),
+            CLLI => parse_content_light_level(&data).map(|value| info.content_light_level = Some(value)).is_ok(),
+            PHYS => parse_physical(&data).map(|value| info.physical = Some(value)).is_ok(),
+            EXIF => Exif::parse(&data).map(|value| info.exif = Some(value)).is_ok(),
+            SBIT => parse_significant_bits(&data, info).map(|value| info.significant_bits = Some(value)).is_ok(),
+            BKGD => parse_background(&data, info, palette).map(|value| info.background = Some(value)).is_ok(),
+            _ if is_text_chunk(&chunk_type) => TextChunk::parse(&chunk_type, &data, max_text_bytes)
+                .map(|text| self.text.push(TextChunk { position, ..text })).is_ok(),
+            _ => false,
+        };
+        if !parsed {
+            self.chunks.push(AncillaryChunk { chunk_type, data, position });
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8], what: &str) -> Result<()> {
+        self.reader.read_exact(buf).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => truncated(what),
+            _ => PngError::Io(e),
+        })?;
+        self.position += buf.len();
+        Ok(())
+    }
+
+    // None at a clean end of file
+    fn read_chunk_header(&mut self) -> Result<Option<ChunkHeader>> {
+        let offset = self.position;
+        let mut length = [0u8; 4];
+        let mut read = 0;
+        while read < 4 {
+            match self.reader.read(&mut length[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(truncated("chunk length")),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(PngError::Io(e)),
+            }
+        }
+        self.position += 4;
+        let length = u32::from_be_bytes(length);
+
+        let mut chunk_type = [0u8; 4];
+        self.read_exact(&mut chunk_type, "chunk type")?;
+
+        self.chunk_count += 1;
+        check_chunk_limits(length, self.chunk_count, &self.options.limits)?;
+        let misplaced = !self.order.check(&chunk_type, Some(&self.info))?;
+        if misplaced {
+            self.report.misplaced_chunks.push(MisplacedChunk { chunk_type, offset });
+        }
+
+        Ok(Some(ChunkHeader { offset, length: length as usize, chunk_type, misplaced }))
+    }
+
+    fn read_chunk_data(&mut self, header: &ChunkHeader) -> Result<Vec<u8>> {
+        // Grows with what is actually read, a lying length can't make us allocate it up front
+        let mut data = Vec::new();
+        (&mut self.reader).take(header.length as u64).read_to_end(&mut data)?;
+        if data.len() < header.length {
+            return Err(truncated("chunk data"));
+        }
+        self.position += header.length;
+
+        let mut hasher = Hasher::new();
+        hasher.update(&header.chunk_type);
+        hasher.update(&data);
+        self.read_crc(header, hasher)?;
+
+        Ok(data)
+    }
+
+    fn read_crc(&mut self, header: &ChunkHeader, hasher: Hasher) -> Result<()> {
+        let mut stored = [0u8; 4];
+        self.read_exact(&mut stored, "chunk CRC")?;
+        let mismatch = CrcMismatch {
+            chunk_type: header.chunk_type,
+            offset: header.offset,
+            stored: u32::from_be_bytes(stored),
+            computed: hasher.finalize(),
+        };
+        verify_crc(mismatch, self.options.crc_mode, &mut self.report)
+    }
+
+    fn start_idat(&mut self, header: ChunkHeader) -> Result<()> {
+        if self.decryption_key.is_some() {
+            let data = self.read_chunk_data(&header)?;
+            self.input = decrypt_chunk(data, self.decryption_key.as_ref())?;
+            self.input_pos = 0;
+        } else {
+            let mut hasher = Hasher::new();
+            hasher.update(&header.chunk_type);
+            let length = header.length;
+            self.idat = Some((header, length, hasher));
+        }
+        Ok(())
+    }
+
+    // Refills the input buffer from the current IDAT chunk, moving on to the next one when it runs out.
+    // Returns false once the IDAT run is over.
+    fn fill_input(&mut self) -> Result<bool> {
+        loop {
+            if let Some((header, remaining, mut hasher)) = self.idat.take() {
+                if remaining > 0 {
+                    let n = remaining.min(INPUT_BUFFER_SIZE);
+                    let mut input = std::mem::take(&mut self.input);
+                    input.resize(n, 0);
+                    self.read_exact(&mut input, "image data")?;
+                    hasher.update(&input);
+                    self.input = input;
+                    self.input_pos = 0;
+                    self.idat = Some((header, remaining - n, hasher));
+                    return Ok(true);
+                }
+                self.read_crc(&header, hasher)?;
+            }
+
+            if self.pending.is_some() {
+                return Ok(false);
+            }
+            match self.read_chunk_header()? {
+                Some(header) if header.chunk_type == IDAT => {
+                    self.start_idat(header)?;
+                    if self.idat.is_none() {
+                        return Ok(true);
+                    }
+                },
+                Some(header) => {
+                    self.pending = Some(header);
+                    return Ok(false);
+                },
+                None => return Ok(false),
+            }
+        }
+    }
+
+    // Returns the next byte of the zlib stream
+    fn next_input_byte(&mut self, what: &str) -> Result<u8> {
+        while self.input_pos == self.input.len() {
+            if !self.fill_input()? {
+                return Err(truncated(what));
+            }
+        }
+        self.input_pos += 1;
+        Ok(self.input[self.input_pos - 1])
+    }
+
+    // Fills `filtered[..length]` with the next inflated bytes
+    fn inflate_into(&mut self, length: usize) -> Result<()> {
+        while self.zlib_header.len() < 2 {
+            let byte = self.next_input_byte("zlib header")?;
+            self.zlib_header.push(byte);
+            if self.zlib_header.len() == 2 {
+                check_header(self.zlib_header[0], self.zlib_header[1])?;
+            }
+        }
+
+        let mut filled = 0;
+        while filled < length {
+            if self.stream_ended {
+                return Err(PngError::Malformed("Image data ended before the last scanline".to_string()));
+            }
+            if self.input_pos == self.input.len() {
+                if !self.fill_input()? {
+                    return Err(truncated("image data"));
+                }
+                continue;
+            }
+
+            let (consumed, produced, status) = self.inflate_step(filled, length)?;
+            self.adler.update(&self.filtered[filled..filled + produced]);
+            filled += produced;
+            if status == Status::StreamEnd {
+                self.stream_ended = true;
+            } else if consumed == 0 && produced == 0 {
+                return Err(PngError::Decompression("inflate made no progress".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    fn inflate_step(&mut self, start: usize, end: usize) -> Result<(usize, usize, Status)> {
+        let (before_in, before_out) = (self.inflater.total_in(), self.inflater.total_out());
+        let status = self.inflater
+            .decompress(&self.input[self.input_pos..], &mut self.filtered[start..end], FlushDecompress::None)
+            .map_err(|e| PngError::Decompression(e.to_string()))?;
+        let consumed = (self.inflater.total_in() - before_in) as usize;
+        let produced = (self.inflater.total_out() - before_out) as usize;
+        self.input_pos += consumed;
+        Ok((consumed, produced, status))
+    }
+
+    // After the last row: the zlib stream must end, its trailer must match, and the remaining chunks are validated
+    fn finish(&mut self) -> Result<()> {
+        while !self.stream_ended {
+            if self.input_pos == self.input.len() {
+                if !self.fill_input()? {
+                    return Err(truncated("image data"));
+                }
+                continue;
+            }
+            // Any byte inflated here is past the size IHDR declares
+            let (consumed, produced, status) = self.inflate_step(0, 1)?;
+            if produced > 0 {
+                return Err(PngError::LimitExceeded("image data inflates to more than IHDR declares".to_string()));
+            }
+            if status == Status::StreamEnd {
+                self.stream_ended = true;
+            } else if consumed == 0 {
+                return Err(PngError::Decompression("inflate made no progress".to_string()));
+            }
+        }
+
+        let mut trailer = [0u8; 4];
+        for byte in trailer.iter_mut() {
+            *byte = self.next_input_byte("zlib Adler-32 trailer")?;
+        }
+        let mismatch = Adler32Mismatch { stored: u32::from_be_bytes(trailer), computed: self.adler.finish() };
+        if mismatch.stored != mismatch.computed {
+            if self.options.crc_mode == CrcMode::Strict {
+                return Err(PngError::BadAdler32(mismatch));
+            }
+            self.report.adler32_mismatch = Some(mismatch);
+        }
+
+        // Skip whatever is left of the IDAT run, then validate the chunks up to IEND
+        while self.fill_input()? {
+            self.input_pos = self.input.len();
+        }
+        let mut next = self.pending.take();
+        while let Some(header) = next {
+            let data = self.read_chunk_data(&header)?;
+            if header.chunk_type == IEND {
+                break;
+            }
+            if !header.misplaced {
+                let data = decrypt_ancillary_chunk(&header.chunk_type, data, self.decryption_key.as_ref())?;
+                let palette = self.info.palette.clone();
+                self.add_ancillary(header.chunk_type, data, ChunkPosition::AfterIdat, palette.as_deref());
+            }
+            next = self.read_chunk_header()?;
+        }
+        self.order.finish()
+    }
+}
+
+fn truncated(what: &str) -> PngError {
+    PngError::Truncated(what.to_string())
+}
+
+// Compressed data is cut into IDAT chunks of this size
+const IDAT_CHUNK_SIZE: usize = 64 * 1024;
+
+// Collects compressed data and writes it out as IDAT chunks whenever a full chunk is ready
+struct IdatWriter<W: Write> {
+    writer: W,
+    buffer: Vec<u8>,
+    encryption_key: Option<[u8; 32]>,
+}
+
+impl<W: Write> IdatWriter<W> {
+    fn write_idat(&mut self, length: usize) -> Result<()> {
+        write_chunk(&mut self.writer, &IDAT, &self.buffer[..length], self.encryption_key.as_ref())?;
+        self.buffer.drain(..length);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for IdatWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= IDAT_CHUNK_SIZE {
+            self.write_idat(IDAT_CHUNK_SIZE).map_err(io::Error::other)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+enum Compressor<W: Write> {
+    Flate(ZlibEncoder<IdatWriter<W>>),
+    Zopfli(BufWriter<zopfli::ZlibEncoder<IdatWriter<W>>>),
+}
+
+impl<W: Write> Compressor<W> {
+    fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            Compressor::Flate(encoder) => encoder.write_all(data)?,
+            Compressor::Zopfli(encoder) => encoder.write_all(data)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<IdatWriter<W>> {
+        Ok(match self {
+            Compressor::Flate(encoder) => encoder.finish()?,
+            Compressor::Zopfli(encoder) => encoder.into_inner().map_err(|e| e.into_error())?.finish()?,
+        })
+    }
+}
+
+// Encodes a PNG one scanline at a time. Each row is filtered as it arrives and fed to a streaming
+// deflater, full IDAT chunks go straight to the writer, so memory use doesn't grow with the image.
+// Rows are in the PNG sample layout of the image's `info`, interlaced images take the rows of each pass
+// in turn, the same order `StreamingDecoder` yields them in.
+pub struct StreamingEncoder<W: Write> {
+    info: PngInfo,
+    compression_level: CompressionLevel,
+    compressor: Compressor<W>,
+    passes: Vec<Pass>,
+    pass_index: usize,
+    row_in_pass: usize,
+    prev: Vec<u8>,
+}
+
+impl<W: Write> StreamingEncoder<W> {
+    // Writes everything up to the image data with `DecodedPng::write_header`, the pixels of `image` aren't
+    // used. Compression levels only pick the deflater and which chunks are kept, rows are never quantized.
+    pub fn new(mut writer: W, image: &DecodedPng, compression_level: CompressionLevel, encryption_key: Option<&[u8; 32]>) -> Result<Self> {
+        let info = &image.info;
+        let image_type = parse_image_type(info.color_type, info.bit_depth);
+        if image_type == ImageType::Unknown {
+            return Err(PngError::Unsupported(format!("color type {} with bit depth {}", info.color_type, info.bit_depth)));
+        }
+        if info.width == 0 || info.height == 0 || info.width > i32::MAX as u32 || info.height > i32::MAX as u32 {
+            return Err(PngError::Malformed(format!("Invalid image dimensions {}x{}", info.width, info.height)));
+        }
+        if info.interlace > 1 {
+            return Err(PngError::Unsupported(format!("interlace method {}", info.interlace)));
+        }
+        // Same palette and tRNS rules the decoder enforces
+        let mut info = PngInfo { image_type, ..info.clone() };
+        let palette = info.palette.take();
+        let transparency = info.transparency.take().map(|transparency| transparency.to_bytes());
+        attach_palette(&mut info, palette, transparency)?;
+
+        image.write_header(&mut writer, &compression_level, &info, encryption_key)?;
+
+        let idat = IdatWriter { writer, buffer: Vec::new(), encryption_key: encryption_key.copied() };
+        let compressor = match compression_level {
+            CompressionLevel::Lossless => Compressor::Flate(ZlibEncoder::new(idat, Compression::fast())),
+            CompressionLevel::Balanced => Compressor::Flate(ZlibEncoder::new(idat, Compression::best())),
+            CompressionLevel::Maximum => Compressor::Zopfli(zopfli::ZlibEncoder::new_buffered(zopfli_options(), zopfli::BlockType::Dynamic, idat)?),
+        };
+
+        let passes = image_passes(info.interlace, info.width as usize, info.height as usize);
+        let mut encoder = StreamingEncoder { info, compression_level, compressor, passes, pass_index: 0, row_in_pass: 0, prev: Vec::new() };
+        encoder.skip_empty_passes();
+        Ok(encoder)
+    }
+
+    #[allow(dead_code)]
+    pub fn info(&self) -> &PngInfo {
+        &self.info
+    }
+
+    // The pass the next row belongs to, None once every row has been written
+    pub fn next_pass(&self) -> Option<&Pass> {
+        self.passes.get(self.pass_index)
+    }
+
+    pub fn write_row(&mut self, row: &[u8]) -> Result<()> {
+        let pass = *self.next_pass().ok_or_else(|| PngError::Malformed("All scanlines have already been written".to_string()))?;
+        let row_bytes = self.info.row_bytes(pass.width);
+        if row.len() != row_bytes {
+            return Err(PngError::Malformed(format!("Scanline is {} bytes, expected {}", row.len(), row_bytes)));
+        }
+
+        let prev = (self.row_in_pass > 0).then_some(&self.prev[..]);
+        let (filter_type, filtered) = choose_best_filter(row, prev, self.info.filter_bytes_per_pixel())?;
+        self.compressor.write_all(&[filter_type])?;
+        self.compressor.write_all(&filtered)?;
+
+        self.prev.clear();
+        self.prev.extend_from_slice(row);
+        self.row_in_pass += 1;
+        if self.row_in_pass == pass.height {
+            self.pass_index += 1;
+            self.row_in_pass = 0;
+            self.skip_empty_passes();
+        }
+        Ok(())
+    }
+
+    // Flushes the rest of the image data and writes the chunks that follow it with `DecodedPng::write_trailer`,
+    // `image` is the one the encoder was created with, plus any chunks read after the image data since.
+    pub fn finish(self, image: &DecodedPng) -> Result<W> {
+        if self.next_pass().is_some() {
+            return Err(truncated("image data, not every scanline was written"));
+        }
+
+        let mut idat = self.compressor.finish()?;
+        if !idat.buffer.is_empty() {
+            idat.write_idat(idat.buffer.len())?;
+        }
+        let encryption_key = idat.encryption_key;
+        let mut writer = idat.writer;
+        image.write_trailer(&mut writer, &self.compression_level, &self.info, encryption_key.as_ref())?;
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    fn skip_empty_passes(&mut self) {
+        while self.next_pass().is_some_and(|pass| pass.is_empty()) {
+            self.pass_index += 1;
+        }
+    }
+}
+
+// Re-encodes a file row by row without holding its pixels. `image` is the decoder's metadata, possibly
+// edited, and is what gets written, the chunks found after the image data are added to it at the end.
+pub fn transcode<R: Read, W: Write>(mut decoder: StreamingDecoder<R>, mut image: DecodedPng, writer: W, compression_level: CompressionLevel, encryption_key: Option<&[u8; 32]>) -> Result<(W, DecodeReport)> {
+    let mut encoder = StreamingEncoder::new(writer, &image, compression_level, encryption_key)?;
+    while let Some(row) = decoder.next_row()? {
+        encoder.write_row(row.data)?;
+    }
+
+    let trailer = decoder.metadata();
+    image.chunks.extend(trailer.chunks.into_iter().filter(|chunk| chunk.position == ChunkPosition::AfterIdat));
+    image.text.extend(trailer.text.into_iter().filter(|text| text.position == ChunkPosition::AfterIdat));
+    let writer = encoder.finish(&image)?;
+    Ok((writer, trailer.report))
+}
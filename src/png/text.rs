@@ -0,0 +1,202 @@
+use std::io::Write;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use crate::png::constants::*;
+use crate::png::error::{PngError, Result};
+use crate::png::types::{ChunkPosition, DecodedPng};
+use crate::png::zlib::inflate;
+
+// Text longer than this is written compressed
+const COMPRESS_THRESHOLD: usize = 1024;
+
+// Extra fields only iTXt carries
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct InternationalText {
+    // RFC 5646 language tag, empty when unknown
+    pub language_tag: String,
+    pub translated_keyword: String,
+}
+
+// One tEXt, zTXt or iTXt chunk. tEXt/zTXt hold Latin-1, which is converted to and from UTF-8 here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextChunk {
+    pub keyword: String,
+    pub text: String,
+    pub compressed: bool,
+    pub international: Option<InternationalText>,
+    // Text after the image data is written back there, new text goes in front with the other metadata
+    pub position: ChunkPosition,
+}
+
+impl TextChunk {
+    // Picks the smallest chunk that can hold the text: tEXt for Latin-1, iTXt otherwise, compressed when long
+    pub fn new(keyword: &str, text: &str) -> TextChunk {
+        TextChunk {
+            keyword: keyword.to_string(),
+            text: text.to_string(),
+            compressed: text.len() > COMPRESS_THRESHOLD,
+            international: (!is_latin1(text)).then(InternationalText::default),
+            position: ChunkPosition::BeforePlte,
+        }
+    }
+
+    pub fn chunk_type(&self) -> [u8; 4] {
+        match (&self.international, self.compressed) {
+            (Some(_), _) => ITXT,
+            (None, true) => ZTXT,
+            (None, false) => TEXT,
+        }
+    }
+
+    pub fn parse(chunk_type: &[u8; 4], data: &[u8], max_text_bytes: u64) -> Result<TextChunk> {
+        let (keyword, rest) = split_null(data, "keyword")?;
+        let keyword = from_latin1(keyword);
+        check_keyword(&keyword)?;
+
+        match *chunk_type {
+            TEXT => Ok(TextChunk { keyword, text: from_latin1(rest), compressed: false, international: None, position: ChunkPosition::BeforePlte }),
+            ZTXT => {
+                let (&method, compressed) = rest.split_first().ok_or_else(|| malformed("zTXt is missing its compression method"))?;
+                let text = decompress(method, compressed, max_text_bytes)?;
+                Ok(TextChunk { keyword, text: from_latin1(&text), compressed: true, international: None, position: ChunkPosition::BeforePlte })
+            },
+            ITXT => {
+                let [flag, method, rest @ ..] = rest else {
+                    return Err(malformed("iTXt is missing its compression fields"));
+                };
+                let (language_tag, rest) = split_null(rest, "language tag")?;
+                let (translated_keyword, text) = split_null(rest, "translated keyword")?;
+                let compressed = match flag {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(malformed(&format!("iTXt compression flag {} is invalid", flag))),
+                };
+                let text = if compressed { decompress(*method, text, max_text_bytes)? } else { text.to_vec() };
+
+                Ok(TextChunk {
+                    keyword,
+                    text: utf8(text)?,
+                    compressed,
+                    international: Some(InternationalText {
+                        language_tag: utf8(language_tag.to_vec())?,
+                        translated_keyword: utf8(translated_keyword.to_vec())?,
+                    }),
+                    position: ChunkPosition::BeforePlte,
+                })
+            },
+            _ => Err(malformed(&format!("{} is not a text chunk", String::from_utf8_lossy(chunk_type)))),
+        }
+    }
+
+    pub fn to_chunk_data(&self) -> Result<Vec<u8>> {
+        check_keyword(&self.keyword)?;
+        let mut data = to_latin1(&self.keyword)?;
+        data.push(0);
+
+        match &self.international {
+            None => {
+                let text = to_latin1(&self.text)?;
+                if self.compressed {
+                    data.push(0);
+                    data.extend_from_slice(&compress(&text)?);
+                } else {
+                    data.extend_from_slice(&text);
+                }
+            },
+            Some(international) => {
+                data.extend_from_slice(&[self.compressed as u8, 0]);
+                data.extend_from_slice(international.language_tag.as_bytes());
+                data.push(0);
+                data.extend_from_slice(international.translated_keyword.as_bytes());
+                data.push(0);
+                if self.compressed {
+                    data.extend_from_slice(&compress(self.text.as_bytes())?);
+                } else {
+                    data.extend_from_slice(self.text.as_bytes());
+                }
+            },
+        }
+        Ok(data)
+    }
+}
+
+impl DecodedPng {
+    #[allow(dead_code)]
+    pub fn text(&self, keyword: &str) -> Option<&str> {
+        self.text.iter().find(|chunk| chunk.keyword == keyword).map(|chunk| chunk.text.as_str())
+    }
+
+    // Replaces every chunk with this keyword
+    pub fn set_text(&mut self, keyword: &str, text: &str) -> Result<()> {
+        check_keyword(keyword)?;
+        self.remove_text(keyword);
+        self.text.push(TextChunk::new(keyword, text));
+        Ok(())
+    }
+
+    pub fn remove_text(&mut self, keyword: &str) {
+        self.text.retain(|chunk| chunk.keyword != keyword);
+    }
+}
+
+pub fn is_text_chunk(chunk_type: &[u8; 4]) -> bool {
+    [TEXT, ZTXT, ITXT].contains(chunk_type)
+}
+
+// 1-79 printable Latin-1 characters, no leading, trailing or consecutive spaces https://www.w3.org/TR/png-3/#11keywords
+pub fn check_keyword(keyword: &str) -> Result<()> {
+    let valid = (1..=79).contains(&keyword.chars().count())
+        && keyword.chars().all(|c| matches!(c as u32, 32..=126 | 161..=255))
+        && !keyword.starts_with(' ')
+        && !keyword.ends_with(' ')
+        && !keyword.contains("  ");
+    if !valid {
+        return Err(malformed(&format!("Invalid text keyword {:?}", keyword)));
+    }
+    Ok(())
+}
+
+fn split_null<'a>(data: &'a [u8], what: &str) -> Result<(&'a [u8], &'a [u8])> {
+    let end = data.iter().position(|&b| b == 0).ok_or_else(|| malformed(&format!("Text chunk {} is not null terminated", what)))?;
+    Ok((&data[..end], &data[end + 1..]))
+}
+
+fn decompress(method: u8, data: &[u8], max_text_bytes: u64) -> Result<Vec<u8>> {
+    if method != 0 {
+        return Err(PngError::Unsupported(format!("text compression method {}", method)));
+    }
+    let (text, adler32_mismatch) = inflate(data, max_text_bytes)?;
+    if let Some(mismatch) = adler32_mismatch {
+        return Err(PngError::BadAdler32(mismatch));
+    }
+    Ok(text)
+}
+
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+fn is_latin1(text: &str) -> bool {
+    text.chars().all(|c| (c as u32) < 256)
+}
+
+fn from_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn to_latin1(text: &str) -> Result<Vec<u8>> {
+    if !is_latin1(text) {
+        return Err(malformed(&format!("{:?} can't be stored as Latin-1", text)));
+    }
+    Ok(text.chars().map(|c| c as u8).collect())
+}
+
+fn utf8(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|_| malformed("iTXt text is not valid UTF-8"))
+}
+
+fn malformed(message: &str) -> PngError {
+    PngError::Malformed(message.to_string())
+}
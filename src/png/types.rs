@@ -1,23 +1,345 @@
+use std::fmt;
 use clap::ValueEnum;
+use crate::png::exif::Exif;
+use crate::png::icc::IccProfile;
+use crate::png::text::TextChunk;
+use crate::png::zlib::Adler32Mismatch;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum ImageType {
     Grayscale,
     Truecolor,
     IndexedColor,
     GrayscaleAlpha,
     TruecolorAlpha,
+    #[default]
     Unknown,
 }
 
-#[derive(ValueEnum, Clone, Debug)]
+impl ImageType {
+    // https://www.w3.org/TR/png-3/#6Colour-values
+    pub fn channels(&self) -> usize {
+        match self {
+            ImageType::Grayscale | ImageType::IndexedColor => 1,
+            ImageType::GrayscaleAlpha => 2,
+            ImageType::Truecolor => 3,
+            ImageType::TruecolorAlpha => 4,
+            ImageType::Unknown => 0,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug, Default)]
 pub enum CompressionLevel{
+    #[default]
     Lossless,
     Balanced,
     Maximum
 }
 
-#[derive(Debug, Clone)]
+// https://www.w3.org/TR/png-3/#11tRNS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transparency {
+    // Sample value (at the image bit depth) that is fully transparent
+    Gray(u16),
+    Rgb(u16, u16, u16),
+    // Alpha for the first n palette entries, the rest are opaque
+    Palette(Vec<u8>),
+}
+
+impl Transparency {
+    // tRNS chunk data
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Transparency::Gray(gray) => gray.to_be_bytes().to_vec(),
+            Transparency::Rgb(r, g, b) => [r, g, b].iter().flat_map(|sample| sample.to_be_bytes()).collect(),
+            Transparency::Palette(alphas) => alphas.clone(),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Interlacing {
+    #[default]
+    None,
+    Adam7,
+    // Encode both ways and keep whichever is smaller
+    Auto,
+}
+
+// How the decoder treats chunk CRCs and the zlib Adler-32 that don't match their data
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CrcMode {
+    // Fail on the first mismatch
+    #[default]
+    Strict,
+    // Record the mismatch in the decode report and keep going
+    Lenient,
+}
+
+// Resource limits checked before anything is allocated, so a small hostile file can't
+// make the decoder reserve gigabytes of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    pub max_chunk_size: u32,
+    // Applies to each zlib stream on its own (image data, zTXt, iCCP...) and to the composited frames of an APNG together
+    pub max_decompressed_bytes: u64,
+    pub max_chunks: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_width: 1 << 20,
+            max_height: 1 << 20,
+            max_pixels: 1 << 29,
+            max_chunk_size: 512 * 1024 * 1024,
+            max_decompressed_bytes: 4 * 1024 * 1024 * 1024,
+            max_chunks: 1 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    pub crc_mode: CrcMode,
+    pub limits: Limits,
+    // Salvage what we can from truncated or corrupted files instead of failing, checksums are then
+    // always lenient. Only the whole file decoder recovers, the streaming decoder still fails.
+    pub recover: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrcMismatch {
+    pub chunk_type: [u8; 4],
+    // Byte offset of the chunk's length field in the file
+    pub offset: usize,
+    pub stored: u32,
+    pub computed: u32,
+}
+
+impl fmt::Display for CrcMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bad CRC in {} chunk at offset {} (stored {:08x}, computed {:08x})",
+            String::from_utf8_lossy(&self.chunk_type), self.offset, self.stored, self.computed)
+    }
+}
+
+// An ancillary chunk that breaks the ordering rules, it's ignored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MisplacedChunk {
+    pub chunk_type: [u8; 4],
+    pub offset: usize,
+}
+
+impl fmt::Display for MisplacedChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} chunk at offset {} is out of place and was ignored", String::from_utf8_lossy(&self.chunk_type), self.offset)
+    }
+}
+
+// Damage worked around in recovery mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Damage {
+    // The file ends inside this chunk, `available` of its `length` data bytes are there
+    TruncatedChunk { chunk_type: [u8; 4], offset: usize, length: usize, available: usize },
+    MissingIend,
+    // A chunk that breaks the critical chunk rules, usually because its type was corrupted
+    SkippedChunk { chunk_type: [u8; 4], offset: usize, reason: String },
+    // An encrypted chunk failed authentication. Image data from a bad IDAT on is dropped, other chunks are left
+    // out, a missing fdAT leaves the animation unreadable
+    UndecryptableChunk { chunk_type: [u8; 4], offset: usize },
+    // The zlib stream stopped early, pixels past the recovered rows are left transparent
+    IncompleteImageData { rows: usize, total_rows: usize, reason: String },
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Damage::TruncatedChunk { chunk_type, offset, length, available } => write!(f, "{} chunk at offset {} is truncated ({} of {} bytes)",
+                String::from_utf8_lossy(chunk_type), offset, available, length),
+            Damage::MissingIend => write!(f, "Missing IEND chunk"),
+            Damage::SkippedChunk { chunk_type, offset, reason } => write!(f, "Skipped {} chunk at offset {} ({})",
+                String::from_utf8_lossy(chunk_type), offset, reason),
+            Damage::UndecryptableChunk { chunk_type, offset } => write!(f, "{} chunk at offset {} could not be decrypted",
+                String::from_utf8_lossy(chunk_type), offset),
+            Damage::IncompleteImageData { rows, total_rows, reason } => write!(f, "Recovered {} of {} rows ({})", rows, total_rows, reason),
+        }
+    }
+}
+
+// Problems the decoder tolerated instead of failing on
+#[derive(Debug, Clone, Default)]
+pub struct DecodeReport {
+    pub crc_mismatches: Vec<CrcMismatch>,
+    pub adler32_mismatch: Option<Adler32Mismatch>,
+    pub misplaced_chunks: Vec<MisplacedChunk>,
+    pub damage: Vec<Damage>,
+}
+
+impl DecodeReport {
+    pub fn messages(&self) -> Vec<String> {
+        let mut messages: Vec<String> = self.crc_mismatches.iter().map(|m| m.to_string()).collect();
+        if let Some(mismatch) = self.adler32_mismatch {
+            messages.push(mismatch.to_string());
+        }
+        messages.extend(self.misplaced_chunks.iter().map(|chunk| chunk.to_string()));
+        messages.extend(self.damage.iter().map(|damage| damage.to_string()));
+        messages
+    }
+}
+
+// cHRM white point and primaries as (x, y), scaled by 100000 like in the chunk https://www.w3.org/TR/png-3/#11cHRM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chromaticities {
+    pub white: (u32, u32),
+    pub red: (u32, u32),
+    pub green: (u32, u32),
+    pub blue: (u32, u32),
+}
+
+// cICP, ITU-T H.273 code points https://www.w3.org/TR/png-3/#cICP-chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cicp {
+    pub colour_primaries: u8,
+    pub transfer_function: u8,
+    // Always 0 for PNG, the samples are RGB
+    pub matrix_coefficients: u8,
+    pub full_range: bool,
+}
+
+pub const TRANSFER_PQ: u8 = 16;
+pub const TRANSFER_HLG: u8 = 18;
+
+impl Cicp {
+    // PQ (SMPTE ST 2084) and HLG (ARIB STD-B67) carry HDR luminance
+    pub fn is_hdr(&self) -> bool {
+        matches!(self.transfer_function, TRANSFER_PQ | TRANSFER_HLG)
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        vec![self.colour_primaries, self.transfer_function, self.matrix_coefficients, self.full_range as u8]
+    }
+}
+
+// mDCV, the display the content was mastered on https://www.w3.org/TR/png-3/#mDCV-chunk
+// Chromaticities are in units of 0.00002, luminances in 0.0001 cd/m²
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MasteringDisplay {
+    // Red, green and blue (x, y)
+    pub primaries: [(u16, u16); 3],
+    pub white: (u16, u16),
+    pub max_luminance: u32,
+    pub min_luminance: u32,
+}
+
+impl MasteringDisplay {
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut data: Vec<u8> = self.primaries.iter().chain([&self.white]).flat_map(|&(x, y)| [x, y]).flat_map(u16::to_be_bytes).collect();
+        data.extend_from_slice(&self.max_luminance.to_be_bytes());
+        data.extend_from_slice(&self.min_luminance.to_be_bytes());
+        data
+    }
+}
+
+// cLLI, brightest pixel and brightest frame average in units of 0.0001 cd/m², 0 when unknown https://www.w3.org/TR/png-3/#cLLI-chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentLightLevel {
+    pub max_cll: u32,
+    pub max_fall: u32,
+}
+
+impl ContentLightLevel {
+    pub fn to_bytes(self) -> Vec<u8> {
+        [self.max_cll, self.max_fall].iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+}
+
+// https://www.w3.org/TR/png-3/#11sRGB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderingIntent {
+    Perceptual = 0,
+    RelativeColorimetric = 1,
+    Saturation = 2,
+    AbsoluteColorimetric = 3,
+}
+
+// Unit of the pHYs pixel densities https://www.w3.org/TR/png-3/#11pHYs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhysicalUnit {
+    // Only the aspect ratio is known
+    Unknown = 0,
+    Meter = 1,
+}
+
+// pHYs, pixels per unit along each axis
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalDimensions {
+    pub x: u32,
+    pub y: u32,
+    pub unit: PhysicalUnit,
+}
+
+const METERS_PER_INCH: f64 = 0.0254;
+
+impl PhysicalDimensions {
+    pub fn from_dpi(dpi: f64) -> PhysicalDimensions {
+        let ppm = (dpi / METERS_PER_INCH).round() as u32;
+        PhysicalDimensions { x: ppm, y: ppm, unit: PhysicalUnit::Meter }
+    }
+
+    pub fn from_ppm(ppm: u32) -> PhysicalDimensions {
+        PhysicalDimensions { x: ppm, y: ppm, unit: PhysicalUnit::Meter }
+    }
+
+    // Horizontal and vertical dots per inch, None when the unit is unknown
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        match self.unit {
+            PhysicalUnit::Meter => Some((self.x as f64 * METERS_PER_INCH, self.y as f64 * METERS_PER_INCH)),
+            PhysicalUnit::Unknown => None,
+        }
+    }
+
+    // pHYs chunk data
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(9);
+        data.extend_from_slice(&self.x.to_be_bytes());
+        data.extend_from_slice(&self.y.to_be_bytes());
+        data.push(self.unit as u8);
+        data
+    }
+}
+
+// sBIT, how many bits of each channel were significant in the original data https://www.w3.org/TR/png-3/#11sBIT
+// Gray is stored as three equal values, `alpha` is None for images without an alpha channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignificantBits {
+    pub rgb: [u8; 3],
+    pub alpha: Option<u8>,
+}
+
+impl SignificantBits {
+    // sBIT chunk data for an image written with this color type and bit depth
+    pub fn to_bytes(self, color_type: u8, bit_depth: u8) -> Option<Vec<u8>> {
+        // Palette entries are always 8 bits
+        let depth = if color_type == 3 { 8 } else { bit_depth };
+        let [red, green, blue] = self.rgb.map(|bits| bits.min(depth));
+        let alpha = self.alpha.unwrap_or(depth).min(depth);
+        let gray = red.max(green).max(blue);
+        match color_type {
+            0 => Some(vec![gray]),
+            2 | 3 => Some(vec![red, green, blue]),
+            4 => Some(vec![gray, alpha]),
+            6 => Some(vec![red, green, blue, alpha]),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 #[allow(dead_code)]
 pub struct PngInfo {
     pub width: u32,
@@ -26,6 +348,61 @@ pub struct PngInfo {
     pub color_type: u8,
     pub interlace: u8,
     pub image_type: ImageType,
+    pub palette: Option<Vec<[u8; 3]>>,
+    pub transparency: Option<Transparency>,
+    // gAMA, the encoding exponent scaled by 100000
+    pub gamma: Option<u32>,
+    pub chromaticities: Option<Chromaticities>,
+    pub srgb: Option<RenderingIntent>,
+    pub icc_profile: Option<IccProfile>,
+    pub physical: Option<PhysicalDimensions>,
+    pub exif: Option<Exif>,
+    // bKGD as 16-bit RGB
+    pub background: Option<[u16; 3]>,
+    pub significant_bits: Option<SignificantBits>,
+    pub cicp: Option<Cicp>,
+    pub mastering_display: Option<MasteringDisplay>,
+    pub content_light_level: Option<ContentLightLevel>,
+}
+
+impl PngInfo {
+    pub fn bits_per_pixel(&self) -> usize {
+        self.image_type.channels() * self.bit_depth as usize
+    }
+
+    // Number of bytes in a scanline of `width` pixels, excluding the filter byte. Sub-byte
+    // pixels are packed and the last byte of a row is padded https://www.w3.org/TR/png-3/#7Scanline
+    pub fn row_bytes(&self, width: usize) -> usize {
+        (width * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    // Filters operate on bytes, for bit depths below 8 the "pixel" to the left is just the previous byte.
+    pub fn filter_bytes_per_pixel(&self) -> usize {
+        (self.bits_per_pixel() / 8).max(1)
+    }
+}
+
+// Where an ancillary chunk sat in the source file, relative to the critical chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkPosition {
+    BeforePlte,
+    // After PLTE (if any) and before the first IDAT
+    BeforeIdat,
+    AfterIdat,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AncillaryChunk {
+    pub chunk_type: [u8; 4],
+    pub data: Vec<u8>,
+    pub position: ChunkPosition,
+}
+
+impl AncillaryChunk {
+    // Bit 5 of the fourth byte https://www.w3.org/TR/png-3/#5Chunk-naming-conventions
+    pub fn is_safe_to_copy(&self) -> bool {
+        self.chunk_type[3] & 0x20 != 0
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -37,8 +414,16 @@ pub struct Pixel {
     pub alpha: u8,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct DecodedPng {
     pub info: PngInfo,
     pub rgba: Vec<u8>,
-}
\ No newline at end of file
+    // Full precision RGBA samples for 16-bit sources, `rgba` then holds the high bytes
+    pub rgba16: Option<Vec<u16>>,
+    pub report: DecodeReport,
+    // Ancillary chunks from the source file in their original order, re-emitted by the encoder
+    pub chunks: Vec<AncillaryChunk>,
+    pub text: Vec<TextChunk>,
+    // Set by edits that rewrite the pixels, unsafe-to-copy chunks no longer describe them after that
+    pub pixels_modified: bool,
+}
@@ -1,8 +1,8 @@
 use std::io::Write;
 use std::num::NonZeroU64;
 use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
-use aes_gcm::aead::{Aead, Generate};
-use anyhow::{Context, Result};
+use aes_gcm::aead::{Aead, Generate, Payload};
+use crate::png::error::{PngError, Result};
 use byteorder::{BigEndian, WriteBytesExt};
 use crc32fast::Hasher;
 use flate2::Compression;
@@ -13,167 +13,327 @@ use zopfli::{compress, Format, Options};
 use crate::png::types::*;
 use crate::png::constants::*;
 use crate::png::optimization::{choose_best_filter, optimize_alpha_channel, quantize_colors};
+use crate::png::parse_image_type;
+use crate::png::interlace::image_passes;
+use crate::png::background::background_chunk;
+use crate::png::color::color_chunks;
 
 impl DecodedPng {
-    pub fn encode_optimized(&self, compression_level: CompressionLevel, encryption_key: Option<&[u8; 32]>, pb: &ProgressBar) -> Result<Vec<u8>> {
+    // HDR images are quantized like any other at lossy levels, see `check_lossy_hdr`
+    pub fn encode_optimized(&self, compression_level: CompressionLevel, interlacing: Interlacing, encryption_key: Option<&[u8; 32]>, pb: &ProgressBar) -> Result<Vec<u8>> {
+        // Kept fdAT chunks would carry the animation frames past the encryption unchanged
+        if encryption_key.is_some() && self.chunks.iter().any(|chunk| chunk.chunk_type == FDAT) {
+            return Err(PngError::Unsupported("encrypting an APNG as a still image, encode it as an AnimatedPng".to_string()));
+        }
         let width = self.info.width as usize;
         let height = self.info.height as usize;
 
         pb.set_message("Optimizing image...");
-        let quantized_rgba = match compression_level {
-            CompressionLevel::Lossless => {
-                &self.rgba[..]
-            },
-            CompressionLevel::Balanced => {
-                &quantize_colors(&self.rgba, 6)
-            },
-            CompressionLevel::Maximum => {
-                &quantize_colors(&self.rgba, 4)
-            }
-        };
-
-        let optimized_rgba = match compression_level {
-            CompressionLevel::Lossless => {
-                &self.rgba[..]
-            },
-            CompressionLevel::Balanced | CompressionLevel::Maximum => {
-                &optimize_alpha_channel(quantized_rgba)
-            }
-        };
-
-        let has_alpha = optimized_rgba.chunks_exact(4).any(|pixel| pixel[3] != 255);
-
+        let optimized_rgba = optimize_pixels(&self.rgba, &compression_level);
         pb.inc(1);
 
-        let (color_type, bytes_per_pixel) = if has_alpha {
-            (6u8, 4usize) // RGBA
-        } else {
-            (2u8, 3usize) // RGB
-        };
-
-        let row_bytes = width * bytes_per_pixel;
-        let mut image_data = Vec::with_capacity(height * row_bytes);
-
-        for y in 0..height {
-            for x in 0..width {
-                let idx = (y * width + x) * 4;
-                image_data.push(optimized_rgba[idx]);     // R
-                image_data.push(optimized_rgba[idx + 1]); // G
-                image_data.push(optimized_rgba[idx + 2]); // B
-                if bytes_per_pixel == 4 {
-                    image_data.push(optimized_rgba[idx + 3]); // A
-                }
-            }
-        }
-
-        // Apply filters and build filtered scanlines
-        pb.set_message("Applying optimal filters...");
-        let mut filtered = Vec::with_capacity(height * (1 + row_bytes));
-        for row in 0..height {
-            let row_start = row * row_bytes;
-            let row_data = &image_data[row_start..row_start + row_bytes];
-
-            let prev_row = if row == 0 {
-                None
-            } else {
-                let prev_start = (row - 1) * row_bytes;
-                Some(&image_data[prev_start..prev_start + row_bytes])
-            };
-
-            let (filter_type, filtered_row) = choose_best_filter(row_data, prev_row, bytes_per_pixel);
-            filtered.push(filter_type);
-            filtered.extend_from_slice(&filtered_row);
-        }
-        pb.inc(1);
+        let rgba16: Vec<&[u16]> = self.rgba16.as_deref().into_iter().collect();
+        let (bit_depth, color_type) = pixel_format(&[&optimized_rgba], &rgba16, &compression_level, self.info.significant_bits);
+        let image_data = pack_image(&optimized_rgba, self.rgba16.as_deref(), color_type, bit_depth);
 
         pb.set_message("Compressing image...");
-        let mut compressed = Vec::new();
+        let (interlace, compressed) = compress_image(&image_data, width, height, color_type, bit_depth, interlacing, &compression_level)?;
+        pb.inc(2);
 
-        match compression_level {
-            CompressionLevel::Lossless => {
-                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
-                encoder.write_all(&filtered)?;
-                compressed = encoder.finish()?;
-            },
-            CompressionLevel::Balanced => {
-                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
-                encoder.write_all(&filtered)?;
-                compressed = encoder.finish()?;
-            },
-            CompressionLevel::Maximum => {
-                let options = Options{
-                    iteration_count: NonZeroU64::new(100).unwrap(),
-                    iterations_without_improvement: NonZeroU64::new(u64::MAX).unwrap(),
-                    maximum_block_splits: 0
-                };
-                compress(options, Format::Zlib, &filtered[..], &mut compressed)?;
-            }
-        }
+        pb.set_message("Writing image...");
+        let format = PngInfo { width: self.info.width, height: self.info.height, bit_depth, color_type, interlace, ..Default::default() };
+        let mut output_bytes = Vec::new();
+        self.write_header(&mut output_bytes, &compression_level, &format, encryption_key)?;
 
+        // Write IDAT chunk
+        write_chunk(&mut output_bytes, &IDAT, &compressed, encryption_key)?;
+
+        self.write_trailer(&mut output_bytes, &compression_level, &format, encryption_key)?;
         pb.inc(1);
 
-        pb.set_message("Writing image...");
-        let mut output_bytes = Vec::new();
+        Ok(output_bytes)
+    }
 
+    // Writes the signature, IHDR and every chunk that goes before the image data. `format` is what the
+    // image data is stored as: its size, sample format, interlace method, and the palette and tRNS to write.
+    // Metadata comes from `self`. With a key the ancillary chunks are encrypted too.
+    pub fn write_header(&self, writer: &mut impl Write, compression_level: &CompressionLevel, format: &PngInfo, encryption_key: Option<&[u8; 32]>) -> Result<()> {
         // Write PNG signature
-        output_bytes.write_all(&PNG_SIG)?;
+        writer.write_all(&PNG_SIG)?;
 
         // Write IHDR chunk
-        let mut ihdr_data = Vec::new();
-        ihdr_data.write_u32::<BigEndian>(self.info.width)?;
-        ihdr_data.write_u32::<BigEndian>(self.info.height)?;
-        ihdr_data.write_u8(8)?; // bit_depth
-        ihdr_data.write_u8(color_type)?;
-        ihdr_data.write_u8(0)?; // compression
-        ihdr_data.write_u8(0)?; // filter
-        ihdr_data.write_u8(0)?; // interlace
-        write_chunk(&mut output_bytes, &IHDR, &ihdr_data, None)?;
+        write_ihdr(writer, format.width, format.height, format.bit_depth, format.color_type, format.interlace)?;
 
-        // Write IDAT chunk
-        write_chunk(&mut output_bytes, &IDAT, &compressed, encryption_key)?;
+        for (chunk_type, data) in color_chunks(&self.info)? {
+            write_ancillary_chunk(writer, &chunk_type, &data, encryption_key)?;
+        }
+        if let Some(data) = self.info.significant_bits.and_then(|bits| bits.to_bytes(format.color_type, format.bit_depth)) {
+            write_ancillary_chunk(writer, &SBIT, &data, encryption_key)?;
+        }
+        for chunk in self.kept_chunks(compression_level, format, ChunkPosition::BeforePlte) {
+            write_ancillary_chunk(writer, &chunk.chunk_type, &chunk.data, encryption_key)?;
+        }
+
+        // PLTE stays readable like the other critical chunks
+        if let Some(palette) = &format.palette {
+            write_chunk(writer, &PLTE, palette.as_flattened(), None)?;
+        }
+        if let Some(transparency) = &format.transparency {
+            write_ancillary_chunk(writer, &TRNS, &transparency.to_bytes(), encryption_key)?;
+        }
+        if let Some(physical) = &self.info.physical {
+            write_ancillary_chunk(writer, &PHYS, &physical.to_bytes(), encryption_key)?;
+        }
+        if let Some(exif) = &self.info.exif {
+            write_ancillary_chunk(writer, &EXIF, &exif.data, encryption_key)?;
+        }
+        if let Some(background) = self.info.background
+            && let Some(data) = background_chunk(background, format.color_type, format.bit_depth, format.palette.as_deref()) {
+            write_ancillary_chunk(writer, &BKGD, &data, encryption_key)?;
+        }
+
+        for chunk in self.kept_chunks(compression_level, format, ChunkPosition::BeforeIdat) {
+            write_ancillary_chunk(writer, &chunk.chunk_type, &chunk.data, encryption_key)?;
+        }
+        for text in self.text.iter().filter(|text| text.position != ChunkPosition::AfterIdat) {
+            write_ancillary_chunk(writer, &text.chunk_type(), &text.to_chunk_data()?, encryption_key)?;
+        }
+        Ok(())
+    }
+
+    // Writes the chunks that follow the image data and IEND, `format` is the one given to `write_header`
+    pub fn write_trailer(&self, writer: &mut impl Write, compression_level: &CompressionLevel, format: &PngInfo, encryption_key: Option<&[u8; 32]>) -> Result<()> {
+        for chunk in self.kept_chunks(compression_level, format, ChunkPosition::AfterIdat) {
+            write_ancillary_chunk(writer, &chunk.chunk_type, &chunk.data, encryption_key)?;
+        }
+        for text in self.text.iter().filter(|text| text.position == ChunkPosition::AfterIdat) {
+            write_ancillary_chunk(writer, &text.chunk_type(), &text.to_chunk_data()?, encryption_key)?;
+        }
 
         // Write IEND chunk
-        write_chunk(&mut output_bytes, &IEND, &[], None)?;
-        pb.inc(1);
+        write_chunk(writer, &IEND, &[], None)
+    }
 
-        Ok(output_bytes)
+    fn kept_chunks<'a>(&'a self, compression_level: &CompressionLevel, format: &PngInfo, position: ChunkPosition) -> impl Iterator<Item = &'a AncillaryChunk> {
+        let pixels_unchanged = matches!(compression_level, CompressionLevel::Lossless)
+            && !self.pixels_modified
+            && format.color_type == self.info.color_type
+            && format.bit_depth == self.info.bit_depth;
+        self.chunks.iter().filter(move |chunk| chunk.position == position && keep_chunk(chunk, pixels_unchanged))
     }
 
-    pub async fn save_optimized_async(&self, path: &str, compression_level: CompressionLevel, encryption_key: Option<[u8; 32]>, pb: &ProgressBar) -> Result<()> {
+    pub async fn save_optimized_async(&self, path: &str, compression_level: CompressionLevel, interlacing: Interlacing, encryption_key: Option<[u8; 32]>, pb: &ProgressBar) -> Result<()> {
         let this = self.clone();
         let pb_clone = pb.clone();
         let encoded_bytes = smol::unblock(move || {
-            this.encode_optimized(compression_level, encryption_key.as_ref(), &pb_clone)
+            this.encode_optimized(compression_level, interlacing, encryption_key.as_ref(), &pb_clone)
         }).await?;
 
         smol::fs::write(path, &encoded_bytes)
             .await
-            .with_context(|| format!("Could not write file {}", path))?;
+            .map_err(|e| PngError::file(e, "write", path))?;
         Ok(())
     }
 
     #[allow(dead_code)]
-    pub fn save_optimized(&self, path: &str, compression_level: CompressionLevel, encryption_key: Option<&[u8; 32]>, pb: &ProgressBar) -> Result<()> {
-        let encoded_bytes = self.encode_optimized(compression_level, encryption_key, pb)?;
-        let mut file = std::fs::File::create(path).with_context(|| format!("Could not create file {}", path))?;
+    pub fn save_optimized(&self, path: &str, compression_level: CompressionLevel, interlacing: Interlacing, encryption_key: Option<&[u8; 32]>, pb: &ProgressBar) -> Result<()> {
+        let encoded_bytes = self.encode_optimized(compression_level, interlacing, encryption_key, pb)?;
+        let mut file = std::fs::File::create(path).map_err(|e| PngError::file(e, "create", path))?;
         file.write_all(&encoded_bytes)?;
         Ok(())
     }
 }
 
-pub fn write_chunk(writer: &mut impl Write, chunk_type: &[u8; 4], data: &[u8], encryption_key: Option<&[u8; 32]>) -> Result<()> {
-    let data_to_write = if let Some(encryption_key) = encryption_key {
-        let cipher = Aes256Gcm::new_from_slice(encryption_key).map_err(|e| anyhow::anyhow!(e))?;
-        let nonce = Nonce::generate();
-        let cipher_text = cipher
-            .encrypt(&nonce, data)
-            .map_err(|e| anyhow::anyhow!(e))?;
+// Chunks with the safe-to-copy bit clear depend on the image data https://www.w3.org/TR/png-3/#5Chunk-naming-conventions
+// The color space chunks don't depend on how samples are stored, so we keep them, any other unsafe
+// chunk only survives when the pixels and their sample format come through unchanged.
+fn keep_chunk(chunk: &AncillaryChunk, pixels_unchanged: bool) -> bool {
+    const COLOR_SPACE_CHUNKS: [[u8; 4]; 7] = [CHRM, GAMA, ICCP, SRGB, CICP, MDCV, CLLI];
+    chunk.is_safe_to_copy() || pixels_unchanged || COLOR_SPACE_CHUNKS.contains(&chunk.chunk_type)
+}
 
-        let mut encrypted_data = Vec::with_capacity(12 + cipher_text.len());
-        encrypted_data.extend_from_slice(nonce.as_slice());
-        encrypted_data.extend_from_slice(&cipher_text);
+// Lossy levels quantize the colors and zero the color of fully transparent pixels
+pub fn optimize_pixels(rgba: &[u8], compression_level: &CompressionLevel) -> Vec<u8> {
+    match compression_level {
+        CompressionLevel::Lossless => rgba.to_vec(),
+        CompressionLevel::Balanced => optimize_alpha_channel(&quantize_colors(rgba, 6)),
+        CompressionLevel::Maximum => optimize_alpha_channel(&quantize_colors(rgba, 4)),
+    }
+}
+
+// Bit depth and color type that hold every frame without loss. Lossy levels quantize well below 8 bits, so
+// only lossless output keeps the 16-bit samples, and only when they hold more than 8 bits of information.
+pub fn pixel_format(rgba: &[&[u8]], rgba16: &[&[u16]], compression_level: &CompressionLevel, significant_bits: Option<SignificantBits>) -> (u8, u8) {
+    // Color type bits are 2 for color and 4 for alpha, so combining frames is a bitwise or
+    let lossless = matches!(compression_level, CompressionLevel::Lossless);
+    if lossless && !rgba16.is_empty() && !rgba16.iter().all(|frame| fits_in_8_bits(frame, significant_bits)) {
+        return (16, rgba16.iter().fold(0, |color_type, frame| color_type | select_color_type(frame, u16::MAX)));
+    }
+    let color_type = rgba.iter().fold(0, |color_type, frame| color_type | select_color_type(frame, 255));
+    let bit_depth = if color_type == 0 { rgba.iter().map(|frame| gray_bit_depth(frame)).max().unwrap_or(8) } else { 8 };
+    (bit_depth, color_type)
+}
 
-        encrypted_data
+// Samples in the order the color type stores them. Sub-byte samples are kept one per byte here and
+// packed into rows by filter_scanlines.
+pub fn pack_image(rgba: &[u8], rgba16: Option<&[u16]>, color_type: u8, bit_depth: u8) -> Vec<u8> {
+    if let (16, Some(rgba16)) = (bit_depth, rgba16) {
+        let mut image_data = Vec::with_capacity(rgba16.len() * 2);
+        pack_pixels(rgba16, color_type, |sample| image_data.extend_from_slice(&sample.to_be_bytes()));
+        return image_data;
+    }
+    let step = 255 / ((1u16 << bit_depth) - 1) as u8;
+    let mut image_data = Vec::with_capacity(rgba.len());
+    pack_pixels(rgba, color_type, |sample| image_data.push(sample / step));
+    image_data
+}
+
+// Filters and compresses packed image data, trying both interlace methods for Auto. Returns the
+// interlace method used along with the zlib stream.
+pub fn compress_image(image_data: &[u8], width: usize, height: usize, color_type: u8, bit_depth: u8, interlacing: Interlacing, compression_level: &CompressionLevel) -> Result<(u8, Vec<u8>)> {
+    let bytes_per_pixel = (parse_image_type(color_type, bit_depth).channels() * bit_depth as usize).div_ceil(8);
+    let candidates = match interlacing {
+        Interlacing::None => vec![0u8],
+        Interlacing::Adam7 => vec![1u8],
+        Interlacing::Auto => vec![0u8, 1u8],
+    };
+
+    let mut best: (u8, Vec<u8>) = (0, Vec::new());
+    for interlace in candidates {
+        let scanlines = filter_scanlines(image_data, width, height, bytes_per_pixel, bit_depth, interlace)?;
+        let compressed = compress_scanlines(&scanlines, compression_level)?;
+        if best.1.is_empty() || compressed.len() < best.1.len() {
+            best = (interlace, compressed);
+        }
+    }
+    Ok(best)
+}
+
+// Picks the smallest color type that can hold every pixel without loss
+fn select_color_type<T: Copy + PartialEq>(rgba: &[T], opaque: T) -> u8 {
+    let has_alpha = rgba.chunks_exact(4).any(|pixel| pixel[3] != opaque);
+    let is_gray = rgba.chunks_exact(4).all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2]);
+
+    match (is_gray, has_alpha) {
+        (true, false) => 0,  // Gray
+        (true, true) => 4,   // Gray + Alpha
+        (false, false) => 2, // RGB
+        (false, true) => 6,  // RGBA
+    }
+}
+
+fn pack_pixels<T: Copy>(rgba: &[T], color_type: u8, mut push: impl FnMut(T)) {
+    for pixel in rgba.chunks_exact(4) {
+        match color_type {
+            0 => push(pixel[0]),
+            4 => {
+                push(pixel[0]);
+                push(pixel[3]);
+            },
+            2 => pixel[..3].iter().for_each(|&sample| push(sample)),
+            _ => pixel.iter().for_each(|&sample| push(sample)),
+        }
+    }
+}
+
+// 16-bit samples can be written as 8-bit without loss when sBIT says no channel has more than 8 significant
+// bits, or when every low byte just repeats the high byte, which is how 8-bit data is scaled up to 16 bits
+fn fits_in_8_bits(rgba16: &[u16], significant_bits: Option<SignificantBits>) -> bool {
+    let significant = significant_bits.is_some_and(|bits| bits.rgb.iter().chain(bits.alpha.iter()).all(|&bits| bits <= 8));
+    significant || rgba16.iter().all(|&sample| sample >> 8 == sample & 0xff)
+}
+
+// Smallest gray bit depth that holds every sample exactly, a sample at depth d is a multiple of 255 / (2^d - 1) at 8 bits
+fn gray_bit_depth(rgba: &[u8]) -> u8 {
+    [1u8, 2, 4].into_iter()
+        .find(|&depth| {
+            let step = 255 / ((1u16 << depth) - 1) as u8;
+            rgba.chunks_exact(4).all(|pixel| pixel[0] % step == 0)
+        })
+        .unwrap_or(8)
+}
+
+// Packs one sample per byte into a row of `bit_depth` bit samples, leftmost pixel in the high bits https://www.w3.org/TR/png-3/#7Scanline
+fn pack_row(samples: &[u8], bit_depth: u8) -> Vec<u8> {
+    let per_byte = 8 / bit_depth as usize;
+    samples.chunks(per_byte)
+        .map(|chunk| chunk.iter().enumerate().fold(0u8, |byte, (i, &sample)| byte | sample << (8 - bit_depth as usize * (i + 1))))
+        .collect()
+}
+
+// Filters every scanline of each pass, rows of a pass only ever predict from the previous row of the same pass
+fn filter_scanlines(image_data: &[u8], width: usize, height: usize, bytes_per_pixel: usize, bit_depth: u8, interlace: u8) -> Result<Vec<u8>> {
+    let mut filtered = Vec::with_capacity(image_data.len() + height * 7);
+
+    for pass in image_passes(interlace, width, height).iter().filter(|pass| !pass.is_empty()) {
+        let row_bytes = pass.width * bytes_per_pixel;
+        let mut prev_row: Option<Vec<u8>> = None;
+
+        for py in 0..pass.height {
+            let mut row_data = Vec::with_capacity(row_bytes);
+            for px in 0..pass.width {
+                let start = pass.image_index(px, py, width) * bytes_per_pixel;
+                row_data.extend_from_slice(&image_data[start..start + bytes_per_pixel]);
+            }
+            if bit_depth < 8 {
+                row_data = pack_row(&row_data, bit_depth);
+            }
+
+            let (filter_type, filtered_row) = choose_best_filter(&row_data, prev_row.as_deref(), bytes_per_pixel)?;
+            filtered.push(filter_type);
+            filtered.extend_from_slice(&filtered_row);
+            prev_row = Some(row_data);
+        }
+    }
+
+    Ok(filtered)
+}
+
+fn compress_scanlines(filtered: &[u8], compression_level: &CompressionLevel) -> Result<Vec<u8>> {
+    let mut compressed = Vec::new();
+
+    match compression_level {
+        CompressionLevel::Lossless => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(filtered)?;
+            compressed = encoder.finish()?;
+        },
+        CompressionLevel::Balanced => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(filtered)?;
+            compressed = encoder.finish()?;
+        },
+        CompressionLevel::Maximum => {
+            compress(zopfli_options(), Format::Zlib, filtered, &mut compressed)?;
+        }
+    }
+
+    Ok(compressed)
+}
+
+pub fn zopfli_options() -> Options {
+    Options{
+        iteration_count: NonZeroU64::new(100).unwrap(),
+        iterations_without_improvement: NonZeroU64::new(u64::MAX).unwrap(),
+        maximum_block_splits: 0
+    }
+}
+
+pub fn write_ihdr(writer: &mut impl Write, width: u32, height: u32, bit_depth: u8, color_type: u8, interlace: u8) -> Result<()> {
+    let mut ihdr_data = Vec::new();
+    ihdr_data.write_u32::<BigEndian>(width)?;
+    ihdr_data.write_u32::<BigEndian>(height)?;
+    ihdr_data.write_u8(bit_depth)?;
+    ihdr_data.write_u8(color_type)?;
+    ihdr_data.write_u8(0)?; // compression
+    ihdr_data.write_u8(0)?; // filter
+    ihdr_data.write_u8(interlace)?;
+    write_chunk(writer, &IHDR, &ihdr_data, None)
+}
+
+pub fn write_chunk(writer: &mut impl Write, chunk_type: &[u8; 4], data: &[u8], encryption_key: Option<&[u8; 32]>) -> Result<()> {
+    let data_to_write = if let Some(encryption_key) = encryption_key {
+        encrypt_data(data, encryption_key, &[])?
     } else {
         data.to_vec()
     };
@@ -189,4 +349,40 @@ pub fn write_chunk(writer: &mut impl Write, chunk_type: &[u8; 4], data: &[u8], e
     writer.write_u32::<BigEndian>(crc)?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Ancillary chunks are encrypted in place like IDAT, with the chunk type as associated data so a payload can't be
+// passed off as another chunk
+pub fn write_ancillary_chunk(writer: &mut impl Write, chunk_type: &[u8; 4], data: &[u8], encryption_key: Option<&[u8; 32]>) -> Result<()> {
+    match encryption_key {
+        Some(key) if SEQUENCED_CHUNKS.contains(chunk_type) => write_chunk(writer, chunk_type, &encrypt_sequenced_chunk(chunk_type, data, key)?, None),
+        Some(key) => write_chunk(writer, chunk_type, &encrypt_data(data, key, chunk_type)?, None),
+        None => write_chunk(writer, chunk_type, data, None),
+    }
+}
+
+// fcTL and fdAT keep their sequence number in front of the encrypted rest. It's authenticated along with the chunk
+// type, so frame controls and frame data can't be edited, swapped around or passed off as each other.
+fn encrypt_sequenced_chunk(chunk_type: &[u8; 4], data: &[u8], encryption_key: &[u8; 32]) -> Result<Vec<u8>> {
+    let Some((sequence, rest)) = data.split_first_chunk::<4>() else {
+        return Err(PngError::Malformed(format!("{} is missing its sequence number", String::from_utf8_lossy(chunk_type))));
+    };
+    let associated_data = [chunk_type.as_slice(), sequence].concat();
+    Ok([sequence.as_slice(), &encrypt_data(rest, encryption_key, &associated_data)?].concat())
+}
+
+// AES-GCM with a fresh nonce, stored in front of the ciphertext. `associated_data` isn't stored but is
+// authenticated, decryption fails unless it gets the same bytes.
+pub fn encrypt_data(data: &[u8], encryption_key: &[u8; 32], associated_data: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new_from_slice(encryption_key).map_err(|_| PngError::Encryption)?;
+    let nonce = Nonce::generate();
+    let cipher_text = cipher
+        .encrypt(&nonce, Payload { msg: data, aad: associated_data })
+        .map_err(|_| PngError::Encryption)?;
+
+    let mut encrypted_data = Vec::with_capacity(12 + cipher_text.len());
+    encrypted_data.extend_from_slice(nonce.as_slice());
+    encrypted_data.extend_from_slice(&cipher_text);
+
+    Ok(encrypted_data)
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+use std::io::Read;
+use crate::png::error::{PngError, Result};
+use flate2::bufread::DeflateDecoder;
+
+// https://www.rfc-editor.org/rfc/rfc1950#section-8
+const ADLER_MOD: u32 = 65521;
+
+// Running Adler-32 for data that arrives in pieces
+#[derive(Debug, Clone, Copy)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Adler32 { a: 1, b: 0 }
+    }
+}
+
+impl Adler32 {
+    pub fn update(&mut self, data: &[u8]) {
+        // 5552 is the largest n such that the sums can't overflow a u32 before reducing
+        for block in data.chunks(5552) {
+            for &byte in block {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= ADLER_MOD;
+            self.b %= ADLER_MOD;
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut adler = Adler32::default();
+    adler.update(data);
+    adler.finish()
+}
+
+// 2 byte zlib header https://www.rfc-editor.org/rfc/rfc1950#section-2.2
+pub fn check_header(cmf: u8, flg: u8) -> Result<()> {
+    if cmf & 0x0f != 8 || !((cmf as u16) << 8 | flg as u16).is_multiple_of(31) {
+        return Err(PngError::Decompression("invalid zlib header".to_string()));
+    }
+    if flg & 0x20 != 0 {
+        return Err(PngError::Decompression("zlib preset dictionaries are not allowed in PNG".to_string()));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adler32Mismatch {
+    pub stored: u32,
+    pub computed: u32,
+}
+
+impl fmt::Display for Adler32Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bad Adler-32 in image data (stored {:08x}, computed {:08x})", self.stored, self.computed)
+    }
+}
+
+// Inflates a zlib stream (the concatenated IDAT data) and checks the Adler-32 trailer ourselves
+// instead of letting flate2 fail on it, so callers can decide whether a mismatch is fatal.
+// Output past `max_output` bytes is never buffered.
+pub fn inflate(data: &[u8], max_output: u64) -> Result<(Vec<u8>, Option<Adler32Mismatch>)> {
+    if data.len() < 2 {
+        return Err(PngError::Truncated("zlib header".to_string()));
+    }
+    check_header(data[0], data[1])?;
+
+    let mut decoder = DeflateDecoder::new(&data[2..]);
+    let mut raw = Vec::new();
+    decoder.by_ref().take(max_output.saturating_add(1)).read_to_end(&mut raw).map_err(|e| PngError::Decompression(e.to_string()))?;
+    if raw.len() as u64 > max_output {
+        return Err(PngError::LimitExceeded(format!("zlib stream inflates to more than {} bytes", max_output)));
+    }
+
+    let trailer_start = 2 + decoder.total_in() as usize;
+    let Some(trailer) = data.get(trailer_start..trailer_start + 4) else {
+        return Err(PngError::Truncated("zlib Adler-32 trailer".to_string()));
+    };
+    let stored = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let computed = adler32(&raw);
+
+    let mismatch = (stored != computed).then_some(Adler32Mismatch { stored, computed });
+    Ok((raw, mismatch))
+}
+
+// Inflates as much of a damaged zlib stream as possible. Returns the output up to the point where
+// the stream broke off and the error that stopped it, if any.
+pub fn inflate_partial(data: &[u8], max_output: u64) -> (Vec<u8>, Option<PngError>) {
+    if data.len() < 2 {
+        return (Vec::new(), Some(PngError::Truncated("zlib header".to_string())));
+    }
+    if let Err(e) = check_header(data[0], data[1]) {
+        return (Vec::new(), Some(e));
+    }
+
+    // read_to_end keeps everything read before an error
+    let mut decoder = DeflateDecoder::new(&data[2..]);
+    let mut raw = Vec::new();
+    if let Err(e) = decoder.by_ref().take(max_output.saturating_add(1)).read_to_end(&mut raw) {
+        return (raw, Some(PngError::Decompression(e.to_string())));
+    }
+    if raw.len() as u64 > max_output {
+        raw.truncate(max_output as usize);
+        return (raw, Some(PngError::LimitExceeded(format!("zlib stream inflates to more than {} bytes", max_output))));
+    }
+
+    let trailer_start = 2 + decoder.total_in() as usize;
+    let error = match data.get(trailer_start..trailer_start + 4) {
+        // A truncated deflate stream just stops producing output, so a missing trailer is how that shows up
+        None => Some(PngError::Truncated("image data".to_string())),
+        Some(trailer) => {
+            let stored = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+            let computed = adler32(&raw);
+            (stored != computed).then_some(PngError::BadAdler32(Adler32Mismatch { stored, computed }))
+        }
+    };
+    (raw, error)
+}